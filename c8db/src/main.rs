@@ -1,15 +1,51 @@
+use std::collections::BTreeMap;
+use std::fmt;
 use std::io::{BufRead, Write};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 
 use structopt::StructOpt;
 
-use core::{Chip8, Error, Key};
+use core::disasm::disassemble;
+use core::{
+    validate_rom, Chip8, Error, Halt, Instruction, Key, Quirks, Res, Step, DEFAULT_TRACE_DEPTH,
+};
+
+/// Number of instructions which can be undone by `back`.
+const HISTORY: usize = 1024;
+
+/// Set by Ctrl-C to stop `continue`.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "c8db", about = "c8db program options.")]
-struct Option {
+struct Args {
     rom: PathBuf,
+    /// Address to load and start the ROM at, e.g. 0x600 for ETI 660 ROMs.
+    #[structopt(long = "start-addr", default_value = "0x200", parse(try_from_str = parse_hex))]
+    start_addr: u16,
+    /// Start executing right away instead of stopping at the entry point.
+    #[structopt(long = "run")]
+    run: bool,
+    /// Follow the quirks of the original COSMAC VIP interpreter.
+    /// Without any of these flags, quirks of known ROMs are detected.
+    #[structopt(long = "cosmac")]
+    cosmac: bool,
+    /// Enable SUPER-CHIP instructions and quirks, e.g. 00FD to exit.
+    #[structopt(long = "schip")]
+    schip: bool,
+    /// Enable XO-CHIP instructions and quirks.
+    #[structopt(long = "xochip")]
+    xochip: bool,
+}
+
+fn parse_hex(s: &str) -> Result<u16, std::num::ParseIntError> {
+    u16::from_str_radix(s.trim_start_matches("0x"), 16)
 }
 
 fn prompt() {
@@ -17,28 +53,577 @@ fn prompt() {
     std::io::stdout().flush().unwrap();
 }
 
+fn print_profile(chip8: &Chip8) {
+    for (pattern, count) in chip8.cpu.opcode_histogram() {
+        println!("{}  {:>10}", pattern, count);
+    }
+}
+
+fn print_backtrace(chip8: &Chip8) {
+    for frame in chip8.cpu.backtrace() {
+        println!("  {}", frame);
+    }
+}
+
+/// Print `count` instructions in RAM from `pc` on, so that code the
+/// program wrote itself shows as it will run.
+fn print_disasm(chip8: &Chip8, pc: u16, count: usize) {
+    // Instructions are at most 4 bytes long.
+    let len = (count * 4).min(chip8.ram.size().saturating_sub(pc as usize));
+    let code = chip8.ram.read_range(pc, len).unwrap_or(&[]);
+    for (addr, opcode, text) in disassemble(code, pc).into_iter().take(count) {
+        println!("{:#05X}: {:04X}  {}", addr, opcode, text);
+    }
+}
+
+fn print_step(step: &Step) {
+    let flow = match step.res {
+        Res::Next => String::new(),
+        Res::Skip => "  (skipped)".to_string(),
+        Res::Jump(to) => format!("  (jumped to {:#05X})", to),
+    };
+    let (pc, opcode, ins) = (step.pc, step.opcode, step.ins);
+    println!("{:#05X}: {:04X}  {}{}", pc, opcode, ins, flow);
+}
+
+/// Execute one instruction. Timers move with the executed instructions
+/// rather than the time spent at the prompt, unless `frozen`.
+fn step(chip8: &mut Chip8, frozen: bool) -> Result<Step, Halt> {
+    let step = chip8.step()?;
+    let per_tick = (chip8.cpu.clock_hz / 60).max(1) as u64;
+    if !frozen && chip8.cpu.cycle_count().is_multiple_of(per_tick) {
+        chip8.tick_timers();
+    }
+    Ok(step)
+}
+
+/// Location whose changes stop `continue`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Watch {
+    V(u8),
+    Mem(u16),
+}
+
+impl Watch {
+    /// Parse `V<x>` or `mem <addr>`.
+    fn parse<'a>(mut words: impl Iterator<Item = &'a str>) -> Option<Watch> {
+        match words.next()? {
+            "mem" => words.next().and_then(|a| parse_hex(a).ok()).map(Watch::Mem),
+            w if w.len() == 2 && w.starts_with(['V', 'v']) => {
+                u8::from_str_radix(&w[1..], 16).ok().map(Watch::V)
+            }
+            _ => None,
+        }
+    }
+
+    /// Current value, `None` if out of RAM.
+    fn value(&self, chip8: &Chip8) -> Option<u8> {
+        match *self {
+            Watch::V(x) => Some(chip8.cpu.state().v[x as usize]),
+            Watch::Mem(addr) => chip8.ram.read(addr).ok(),
+        }
+    }
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Watch::V(x) => write!(f, "V{:X}", x),
+            Watch::Mem(addr) => write!(f, "mem {:#05X}", addr),
+        }
+    }
+}
+
+/// Message for the first of `watchpoints` changed by the instruction at
+/// `pc`, whose new value is remembered.
+fn watched_change(chip8: &Chip8, watchpoints: &mut BTreeMap<Watch, u8>, pc: u16) -> Option<String> {
+    for (w, old) in watchpoints.iter_mut() {
+        let new = w.value(chip8).unwrap_or(*old);
+        if new != *old {
+            let change = format!(
+                "watchpoint: {} changed {:#04X} -> {:#04X} at {:#05X}",
+                w, old, new, pc
+            );
+            *old = new;
+            return Some(change);
+        }
+    }
+    None
+}
+
+/// Register or number compared by a breakpoint condition.
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    V(u8),
+    I,
+    Dt,
+    St,
+    Num(u16),
+}
+
+impl Operand {
+    /// Parse `V<x>`, `I`, `DT`, `ST`, a `0x` prefixed hex or a decimal number.
+    fn parse(s: &str) -> Option<Operand> {
+        let up = s.to_ascii_uppercase();
+        let op = match up.as_str() {
+            "I" => Operand::I,
+            "DT" => Operand::Dt,
+            "ST" => Operand::St,
+            _ if up.len() == 2 && up.starts_with('V') => {
+                Operand::V(u8::from_str_radix(&up[1..], 16).ok()?)
+            }
+            _ if up.starts_with("0X") => Operand::Num(u16::from_str_radix(&up[2..], 16).ok()?),
+            _ => Operand::Num(up.parse().ok()?),
+        };
+        Some(op)
+    }
+
+    fn value(&self, chip8: &Chip8) -> u16 {
+        let state = chip8.cpu.state();
+        match *self {
+            Operand::V(x) => state.v[x as usize] as u16,
+            Operand::I => state.i,
+            Operand::Dt => state.dt as u16,
+            Operand::St => state.st as u16,
+            Operand::Num(n) => n,
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::V(x) => write!(f, "V{:X}", x),
+            Operand::I => write!(f, "I"),
+            Operand::Dt => write!(f, "DT"),
+            Operand::St => write!(f, "ST"),
+            Operand::Num(n) => write!(f, "{:#X}", n),
+        }
+    }
+}
+
+/// Comparison operators of breakpoint conditions.
+const OPERATORS: [&str; 6] = ["==", "!=", "<=", ">=", "<", ">"];
+
+/// Condition of `break <addr> if <lhs> <op> <rhs>`, e.g. `V0 == 0x10`.
+#[derive(Debug, Clone, Copy)]
+struct Cond {
+    lhs: Operand,
+    op: &'static str,
+    rhs: Operand,
+}
+
+impl Cond {
+    fn parse(expr: &str) -> Option<Cond> {
+        let (pos, op) = OPERATORS
+            .iter()
+            .filter_map(|op| expr.find(op).map(|pos| (pos, *op)))
+            .min_by_key(|(pos, op)| (*pos, std::cmp::Reverse(op.len())))?;
+        let lhs = Operand::parse(expr[..pos].trim())?;
+        let rhs = Operand::parse(expr[pos + op.len()..].trim())?;
+        Some(Cond { lhs, op, rhs })
+    }
+
+    fn eval(&self, chip8: &Chip8) -> bool {
+        let (l, r) = (self.lhs.value(chip8), self.rhs.value(chip8));
+        match self.op {
+            "==" => l == r,
+            "!=" => l != r,
+            "<=" => l <= r,
+            ">=" => l >= r,
+            "<" => l < r,
+            _ => l > r,
+        }
+    }
+}
+
+impl fmt::Display for Cond {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.lhs, self.op, self.rhs)
+    }
+}
+
+/// Print breakpoint at `addr`, with its condition if any.
+fn print_breakpoint(addr: u16, cond: &Option<Cond>) {
+    match cond {
+        Some(cond) => println!("breakpoint at {:#05X} if {}", addr, cond),
+        None => println!("breakpoint at {:#05X}", addr),
+    }
+}
+
+/// Execute until the program halts, reaches one of `breakpoints` with its
+/// condition met if any, or
+/// changes one of `watchpoints`, which keep the values last seen.
+/// Also stops on Ctrl-C and when the program waits for a key.
+fn cont(
+    chip8: &mut Chip8,
+    frozen: bool,
+    breakpoints: &BTreeMap<u16, Option<Cond>>,
+    watchpoints: &mut BTreeMap<Watch, u8>,
+) -> Result<(), Halt> {
+    // Changes made by stepping since the last `continue` don't count.
+    for (w, old) in watchpoints.iter_mut() {
+        *old = w.value(chip8).unwrap_or(*old);
+    }
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    // Only while running, so Ctrl-C still quits at the prompt.
+    let handler = interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    let previous = unsafe { libc::signal(libc::SIGINT, handler) };
+    let res = run_until_stopped(chip8, frozen, breakpoints, watchpoints);
+    unsafe { libc::signal(libc::SIGINT, previous) };
+    res
+}
+
+fn run_until_stopped(
+    chip8: &mut Chip8,
+    frozen: bool,
+    breakpoints: &BTreeMap<u16, Option<Cond>>,
+    watchpoints: &mut BTreeMap<Watch, u8>,
+) -> Result<(), Halt> {
+    loop {
+        let step = step(chip8, frozen)?;
+        let pc = step.pc;
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            println!("interrupted at {:#05X}", chip8.cpu.pc);
+            return Ok(());
+        }
+        // Nothing else can happen until a key is sent by `key`.
+        if let (Instruction::LdVxK(_), Res::Jump(to)) = (step.ins, step.res) {
+            if to == pc {
+                println!("waiting for a key at {:#05X}", pc);
+                return Ok(());
+            }
+        }
+        if let Some(change) = watched_change(chip8, watchpoints, pc) {
+            println!("{}", change);
+            return Ok(());
+        }
+        if let Some(cond) = breakpoints.get(&chip8.cpu.pc) {
+            if cond.is_none_or(|c| c.eval(chip8)) {
+                print_breakpoint(chip8.cpu.pc, cond);
+                return Ok(());
+            }
+        }
+    }
+}
+
 fn main() -> Result<(), Error> {
-    let opts = Option::from_args();
+    let opts = Args::from_args();
     env_logger::init();
-    let mut chip8 = Chip8::new();
-    let rom = &opts.rom.canonicalize().unwrap();
-    let file = std::fs::File::open(&rom.to_str().unwrap()).unwrap();
-    chip8.ram.load(file)?;
+    let rom = std::fs::read(&opts.rom)?;
+    let quirks = if opts.xochip {
+        Quirks::xochip()
+    } else if opts.schip {
+        Quirks::schip()
+    } else if opts.cosmac {
+        Quirks::chip8()
+    } else {
+        Chip8::detect_quirks(&rom).unwrap_or_default()
+    };
     let (kb, rx) = mpsc::channel();
-    let mut rx = Some(rx);
+    let mut chip8 = Chip8::builder()
+        .quirks(quirks)
+        .input(Box::new(rx))
+        .history(HISTORY)
+        .trace(DEFAULT_TRACE_DEPTH)
+        .manual_timers()
+        .start_addr(opts.start_addr)
+        .build();
+    println!("{}", validate_rom(&rom));
+    chip8.load_rom_bytes(&rom)?;
+    chip8.cpu.set_profiling(true);
+    if let Some(rom_info) = chip8.rom_info() {
+        println!("{}", rom_info);
+    }
 
-    let cpu = &mut chip8.cpu;
-    let ram = &mut chip8.ram;
     let stdin = std::io::stdin();
+    // Set once the program stops, e.g. via 00FD, so stepping doesn't re-run it.
+    let mut halted = None;
+    let mut frozen = false;
+    let mut breakpoints = BTreeMap::new();
+    let mut watchpoints = BTreeMap::new();
+    // Stop at the entry point like a debugger with a breakpoint there.
+    print_disasm(&chip8, chip8.cpu.pc, 1);
+    if opts.run {
+        if let Err(halt) = cont(&mut chip8, frozen, &breakpoints, &mut watchpoints) {
+            println!("{}", halt);
+            halted = Some(halt);
+        }
+    }
     loop {
         prompt();
         let mut line = String::new();
-        stdin.lock().read_line(&mut line).unwrap();
-        cpu.cycle(ram, &mut None, &mut rx);
+        if stdin.lock().read_line(&mut line)? == 0 {
+            break;
+        }
 
-        if !line.is_empty() {
-            kb.send(Key(line.chars().next().unwrap().to_digit(10).unwrap() as u8))
-                .unwrap();
+        let mut words = line.split_whitespace();
+        match words.next() {
+            None | Some("s") | Some("step") => match &halted {
+                Some(halt) => println!("{}", halt),
+                None => match step(&mut chip8, frozen) {
+                    Ok(step) => print_step(&step),
+                    Err(halt) => {
+                        println!("{}", halt);
+                        halted = Some(halt);
+                    }
+                },
+            },
+            Some("c") | Some("continue") => match &halted {
+                Some(halt) => println!("{}", halt),
+                None => {
+                    if let Err(halt) = cont(&mut chip8, frozen, &breakpoints, &mut watchpoints) {
+                        println!("{}", halt);
+                        halted = Some(halt);
+                    }
+                }
+            },
+            Some("br") | Some("break") => {
+                let rest: Vec<_> = words.collect();
+                let bp = match rest[..] {
+                    [addr] => parse_hex(addr).ok().map(|addr| (addr, None)),
+                    [addr, "if", ref expr @ ..] => parse_hex(addr)
+                        .ok()
+                        .zip(Cond::parse(&expr.join(" ")))
+                        .map(|(addr, cond)| (addr, Some(cond))),
+                    _ => None,
+                };
+                match bp {
+                    Some((addr, cond)) => {
+                        breakpoints.insert(addr, cond);
+                    }
+                    None if rest.is_empty() => {
+                        for (addr, cond) in &breakpoints {
+                            print_breakpoint(*addr, cond);
+                        }
+                    }
+                    None => println!("usage: break [addr [if <lhs> <op> <rhs>]]"),
+                }
+            }
+            Some("watch") => {
+                let rest: Vec<_> = words.collect();
+                if rest.is_empty() {
+                    for w in watchpoints.keys() {
+                        println!("watchpoint on {}", w);
+                    }
+                } else {
+                    match Watch::parse(rest.into_iter()) {
+                        Some(w) => match w.value(&chip8) {
+                            Some(val) => {
+                                watchpoints.insert(w, val);
+                            }
+                            None => println!("{} is out of RAM", w),
+                        },
+                        None => println!("usage: watch [V<x> | mem <addr>]"),
+                    }
+                }
+            }
+            Some("del") | Some("delete") => {
+                let rest: Vec<_> = words.collect();
+                let addr = match rest[..] {
+                    [addr] => parse_hex(addr).ok(),
+                    _ => None,
+                };
+                if let Some(w) = Watch::parse(rest.into_iter()) {
+                    if watchpoints.remove(&w).is_none() {
+                        println!("no watchpoint on {}", w);
+                    }
+                } else if let Some(addr) = addr {
+                    if breakpoints.remove(&addr).is_none() {
+                        println!("no breakpoint at {:#05X}", addr);
+                    }
+                } else {
+                    println!("usage: delete <addr> | delete V<x> | delete mem <addr>");
+                }
+            }
+            Some("b") | Some("back") => {
+                if chip8.step_back() {
+                    halted = None;
+                } else {
+                    println!("no more history");
+                }
+            }
+            Some("k") | Some("key") => {
+                match words
+                    .next()
+                    .and_then(|k| u8::from_str_radix(k, 16).ok())
+                    .and_then(Key::from_nibble)
+                {
+                    Some(k) => kb.send(k).unwrap(),
+                    None => println!("usage: key <0-F>"),
+                }
+            }
+            Some("d") | Some("disasm") => {
+                let count = words.next().and_then(|n| n.parse().ok()).unwrap_or(10);
+                print_disasm(&chip8, chip8.cpu.pc, count);
+            }
+            Some("r") | Some("regs") => {
+                let state = chip8.cpu.state();
+                for (n, v) in state.v.iter().enumerate() {
+                    print!("V{:X}={:02X} ", n, v);
+                }
+                println!();
+                println!(
+                    "I={:#05X} SP={} DT={} ST={} stack={:X?}",
+                    state.i,
+                    state.sp,
+                    state.dt,
+                    state.st,
+                    state.call_stack()
+                );
+                if let Some(ins) = state.last {
+                    println!("last: {}", ins);
+                }
+            }
+            Some("bt") | Some("backtrace") => print_backtrace(&chip8),
+            Some("t") | Some("trace") => {
+                let trace = chip8.cpu.trace_dump();
+                let count = words.next().and_then(|n| n.parse().ok()).unwrap_or(10);
+                for (pc, opcode) in trace.iter().skip(trace.len().saturating_sub(count)) {
+                    println!("{:#05X}: {:04X}", pc, opcode);
+                }
+            }
+            Some("x") => {
+                let addr = words
+                    .next()
+                    .and_then(|a| parse_hex(a).ok())
+                    .unwrap_or_else(|| chip8.cpu.state().i);
+                let len = words.next().and_then(|n| n.parse().ok()).unwrap_or(64);
+                print!("{}", chip8.ram.hexdump(addr, len));
+            }
+            Some("f") | Some("freeze") => {
+                frozen = !frozen;
+                println!("timers {}", if frozen { "frozen" } else { "running" });
+            }
+            Some("p") | Some("profile") => print_profile(&chip8),
+            Some("q") | Some("quit") => break,
+            Some(cmd) => println!("unknown command: {}", cmd),
         }
+        println!(
+            "pc={:#05X} cycles={}",
+            chip8.cpu.pc,
+            chip8.cpu.cycle_count()
+        );
+    }
+    print_profile(&chip8);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn start_addr_is_hex() {
+        assert_eq!(parse_hex("0x600"), Ok(0x600));
+        assert_eq!(parse_hex("200"), Ok(0x200));
+        assert!(parse_hex("0x10000").is_err());
+        assert!(parse_hex("start").is_err());
+    }
+
+    #[test]
+    fn watch_parse() {
+        let parse = |s: &str| Watch::parse(s.split_whitespace());
+        assert_eq!(parse("V3"), Some(Watch::V(3)));
+        assert_eq!(parse("vf"), Some(Watch::V(0xF)));
+        assert_eq!(parse("mem 0x300"), Some(Watch::Mem(0x300)));
+        assert_eq!(parse("mem 300"), Some(Watch::Mem(0x300)));
+        for bad in ["", "V", "VG", "V10", "mem", "mem xyz", "I"].iter() {
+            assert_eq!(parse(bad), None, "{}", bad);
+        }
+        assert_eq!(Watch::V(0xA).to_string(), "VA");
+        assert_eq!(Watch::Mem(0x300).to_string(), "mem 0x300");
+    }
+
+    /// Chip8 which ran `LD V3, 5` of `LD V3, 5; LD V3, 5; LD V3, 10; JP 0x206`.
+    fn v3_program() -> Chip8 {
+        let mut chip8 = Chip8::builder().build();
+        let rom = [0x63, 0x05, 0x63, 0x05, 0x63, 0x0A, 0x12, 0x06];
+        chip8.load_rom_bytes(&rom).unwrap();
+        chip8.step().unwrap();
+        chip8
+    }
+
+    #[test]
+    fn changed_register_stops_continue() {
+        let mut chip8 = v3_program();
+        let mut watchpoints = BTreeMap::new();
+        // The value cached when the watchpoint was added is refreshed.
+        watchpoints.insert(Watch::V(3), 0);
+        let res = cont(&mut chip8, true, &BTreeMap::new(), &mut watchpoints);
+        assert_eq!(res, Ok(()));
+        // Writing the same 5 again isn't a change, 10 is.
+        assert_eq!(chip8.cpu.pc, 0x206);
+        assert_eq!(watchpoints[&Watch::V(3)], 0x0A);
+    }
+
+    #[test]
+    fn watched_change_message() {
+        let mut chip8 = v3_program();
+        let mut watchpoints = BTreeMap::new();
+        watchpoints.insert(Watch::V(3), 5);
+        chip8.step().unwrap();
+        assert_eq!(watched_change(&chip8, &mut watchpoints, 0x202), None);
+        chip8.step().unwrap();
+        assert_eq!(
+            watched_change(&chip8, &mut watchpoints, 0x204),
+            Some("watchpoint: V3 changed 0x05 -> 0x0A at 0x204".to_string())
+        );
+        assert_eq!(watched_change(&chip8, &mut watchpoints, 0x204), None);
+    }
+
+    #[test]
+    fn operand_parse() {
+        let parse = |s: &str| Operand::parse(s).map(|op| op.to_string());
+        assert_eq!(parse("v3").as_deref(), Some("V3"));
+        assert_eq!(parse("VF").as_deref(), Some("VF"));
+        assert_eq!(parse("i").as_deref(), Some("I"));
+        assert_eq!(parse("DT").as_deref(), Some("DT"));
+        assert_eq!(parse("st").as_deref(), Some("ST"));
+        // Decimal unless prefixed with 0x.
+        assert_eq!(parse("10").as_deref(), Some("0xA"));
+        assert_eq!(parse("0x10").as_deref(), Some("0x10"));
+        assert_eq!(parse("0X1f").as_deref(), Some("0x1F"));
+        for bad in ["", "VG", "V10", "0x", "0xG", "1a", "-1", "65536", "PC"].iter() {
+            assert_eq!(parse(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn cond_parse() {
+        let parse = |s: &str| Cond::parse(s).map(|c| c.to_string());
+        assert_eq!(parse("V0 == 0x10").as_deref(), Some("V0 == 0x10"));
+        assert_eq!(parse("I!=DT").as_deref(), Some("I != DT"));
+        // `<=` and `>=` aren't read as `<` or `>` followed by `=5`.
+        assert_eq!(parse("V1<=5").as_deref(), Some("V1 <= 0x5"));
+        assert_eq!(parse("V1 >= 5").as_deref(), Some("V1 >= 0x5"));
+        assert_eq!(parse("V1 < 5").as_deref(), Some("V1 < 0x5"));
+        assert_eq!(parse("V1 > 5").as_deref(), Some("V1 > 0x5"));
+        for bad in ["", "V0", "V0 =", "== 5", "V0 = 5", "V0 == x", "VG < 1"].iter() {
+            assert_eq!(parse(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn cond_eval() {
+        let mut chip8 = v3_program();
+        let holds = |chip8: &Chip8, s: &str| Cond::parse(s).unwrap().eval(chip8);
+        assert!(holds(&chip8, "V3 == 5"));
+        assert!(holds(&chip8, "V3 <= 5"));
+        assert!(!holds(&chip8, "V3 < 5"));
+        assert!(holds(&chip8, "5 >= V3"));
+        chip8.step().unwrap();
+        chip8.step().unwrap();
+        assert!(holds(&chip8, "V3 > 5"));
+        assert!(holds(&chip8, "V3 != 0x5"));
+    }
+
+    #[test]
+    fn start_addr_defaults_to_0x200() {
+        let opts = Args::from_iter(&["c8db", "rom.ch8"]);
+        assert_eq!(opts.start_addr, 0x200);
+        let opts = Args::from_iter(&["c8db", "--start-addr", "0x600", "rom.ch8"]);
+        assert_eq!(opts.start_addr, 0x600);
     }
 }
@@ -0,0 +1,255 @@
+use std::ops::ControlFlow;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread::sleep;
+use std::time::Duration;
+
+use log::*;
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use sdl2::rect::Point;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+use sdl2::EventPump;
+use structopt::StructOpt;
+
+use core::{Audio, Chip8, FrameBuffer, Halt, Key, Quirks, HEIGHT, WIDTH};
+
+/// Frequency of the beep in Hz.
+const BEEP_HZ: f32 = 440.0;
+
+/// Volume of the beep from 0 to 1.
+const BEEP_VOLUME: f32 = 0.1;
+
+/// RGB of a pixel by the mask of XO-CHIP planes it's on in.
+const PALETTE: [(u8, u8, u8); 4] = [(0, 0, 0), (255, 255, 255), (0, 255, 255), (255, 0, 255)];
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "chip8-sdl", about = "chip8-sdl program options.")]
+struct Args {
+    rom: PathBuf,
+    /// Frames drawn per second, at least 1.
+    #[structopt(short = "f", long = "fps", default_value = "60", parse(try_from_str = parse_fps))]
+    fps: u32,
+    /// Window pixels per Chip8 low resolution pixel.
+    #[structopt(short = "s", long = "scale", default_value = "10")]
+    scale: u32,
+    /// CPU speed in instructions per second, at least 1.
+    #[structopt(short = "c", long = "clock", default_value = "500", parse(try_from_str = parse_clock))]
+    clock: u32,
+    /// Follow the quirks of the original COSMAC VIP interpreter.
+    /// Without any of these flags, quirks of known ROMs are detected.
+    #[structopt(long = "cosmac")]
+    cosmac: bool,
+    /// Enable SUPER-CHIP instructions and quirks.
+    #[structopt(long = "schip")]
+    schip: bool,
+    /// Enable XO-CHIP instructions and quirks.
+    #[structopt(long = "xochip")]
+    xochip: bool,
+}
+
+fn parse_fps(s: &str) -> Result<u32, String> {
+    match s.parse() {
+        Ok(fps) if fps > 0 => Ok(fps),
+        _ => Err(format!("fps must be a positive number, not `{}`", s)),
+    }
+}
+
+fn parse_clock(s: &str) -> Result<u32, String> {
+    match s.parse() {
+        Ok(hz) if hz > 0 => Ok(hz),
+        _ => Err(format!("clock must be a positive number, not `{}`", s)),
+    }
+}
+
+/// Square wave played while the sound timer is nonzero.
+struct SquareWave {
+    /// Part of a period advanced per sample.
+    step: f32,
+    phase: f32,
+}
+
+impl AudioCallback for SquareWave {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        for x in out.iter_mut() {
+            *x = if self.phase < 0.5 {
+                BEEP_VOLUME
+            } else {
+                -BEEP_VOLUME
+            };
+            self.phase = (self.phase + self.step) % 1.0;
+        }
+    }
+}
+
+struct Beeper {
+    device: AudioDevice<SquareWave>,
+}
+
+impl Audio for Beeper {
+    fn play(&mut self) {
+        self.device.resume();
+    }
+
+    fn pause(&mut self) {
+        self.device.pause();
+    }
+}
+
+/// Open the default playback device, `None` without one.
+fn beeper(sdl: &sdl2::Sdl) -> Option<Beeper> {
+    let spec = AudioSpecDesired {
+        freq: Some(44100),
+        channels: Some(1),
+        samples: None,
+    };
+    let device = sdl
+        .audio()
+        .and_then(|audio| {
+            audio.open_playback(None, &spec, |spec| SquareWave {
+                step: BEEP_HZ / spec.freq as f32,
+                phase: 0.0,
+            })
+        })
+        .map_err(|e| warn!("Unable to open audio, running silent: {}", e))
+        .ok()?;
+    Some(Beeper { device })
+}
+
+/// Chip8 key of `keycode` on the QWERTY layout of `1234`/`qwer`/`asdf`/`zxcv`.
+fn key(keycode: Keycode) -> Option<Key> {
+    let name = keycode.name().to_lowercase();
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Key::try_from_char(c),
+        _ => None,
+    }
+}
+
+/// Handle the events since the last frame, breaking when the window is
+/// closed or Esc is pressed.
+fn handle_events(events: &mut EventPump, keypad: &mpsc::Sender<Key>) -> ControlFlow<()> {
+    for event in events.poll_iter() {
+        match event {
+            Event::Quit { .. }
+            | Event::KeyDown {
+                keycode: Some(Keycode::Escape),
+                ..
+            } => return ControlFlow::Break(()),
+            Event::KeyDown {
+                keycode: Some(keycode),
+                repeat: false,
+                ..
+            } => {
+                if let Some(k) = key(keycode) {
+                    debug!("sending key {:?}", k);
+                    keypad.send(k).unwrap();
+                }
+            }
+            _ => {}
+        }
+    }
+    ControlFlow::Continue(())
+}
+
+/// Draw `fb` scaled to the whole window.
+fn render(canvas: &mut Canvas<Window>, fb: &FrameBuffer) -> Result<(), String> {
+    let res = fb.resolution();
+    let (width, height) = (res.width(), res.height());
+    if canvas.logical_size() != (width as u32, height as u32) {
+        canvas
+            .set_logical_size(width as u32, height as u32)
+            .map_err(|e| e.to_string())?;
+    }
+    canvas.set_draw_color(Color::from(PALETTE[0]));
+    canvas.clear();
+    let pixels = fb.pixels();
+    for x in 0..width {
+        for y in 0..height {
+            let p = (pixels[x][y] & 0x3) as usize;
+            if p != 0 {
+                canvas.set_draw_color(Color::from(PALETTE[p]));
+                canvas.draw_point(Point::new(x as i32, y as i32))?;
+            }
+        }
+    }
+    canvas.present();
+    Ok(())
+}
+
+fn run(opts: Args) -> Result<(), String> {
+    let sdl = sdl2::init()?;
+    let window = sdl
+        .video()?
+        .window(
+            "chip8",
+            WIDTH as u32 * opts.scale,
+            HEIGHT as u32 * opts.scale,
+        )
+        .position_centered()
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+    let mut events = sdl.event_pump()?;
+
+    let rom = std::fs::read(&opts.rom).map_err(|e| e.to_string())?;
+    let quirks = if opts.xochip {
+        Quirks::xochip()
+    } else if opts.schip {
+        Quirks::schip()
+    } else if opts.cosmac {
+        Quirks::chip8()
+    } else {
+        Chip8::detect_quirks(&rom).unwrap_or_default()
+    };
+    let (keypad, rx) = mpsc::channel();
+    let mut builder = Chip8::builder()
+        .input(Box::new(rx))
+        .quirks(quirks)
+        .clock_hz(opts.clock);
+    if let Some(beeper) = beeper(&sdl) {
+        builder = builder.audio(Box::new(beeper));
+    }
+    let mut chip8 = builder.build();
+    chip8.load_rom_bytes(&rom).map_err(|e| e.to_string())?;
+
+    let mut error = None;
+    let res = chip8.run_at(opts.clock, opts.fps, |chip8| {
+        if let Err(e) = render(&mut canvas, &chip8.fb) {
+            error = Some(e);
+            return ControlFlow::Break(());
+        }
+        handle_events(&mut events, &keypad)
+    });
+    if let Some(e) = error {
+        return Err(e);
+    }
+    match res {
+        Ok(()) | Err(Halt::Exit) => Ok(()),
+        // Keep showing the last screen until the window is closed.
+        Err(Halt::SpinLoop(_)) => {
+            info!("program finished");
+            while handle_events(&mut events, &keypad).is_continue() {
+                render(&mut canvas, &chip8.fb)?;
+                sleep(Duration::from_millis(1000 / opts.fps as u64));
+            }
+            Ok(())
+        }
+        Err(halt) => Err(halt.to_string()),
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let opts = Args::from_args();
+    if let Err(e) = run(opts) {
+        error!("{}", e);
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
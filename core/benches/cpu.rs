@@ -0,0 +1,68 @@
+//! CPU throughput, reported by Criterion as the time of one `Chip8::tick`,
+//! i.e. ns/instruction. Run with `cargo bench` in `core`; Criterion
+//! compares against the previous run, so run it before and after a change.
+//!
+//! Measured on a one-core Intel Xeon VM with rustc 1.95: `alu loop` takes
+//! about 135 ns, `draw loop` about 150 ns and `wide draw loop` about 345 ns
+//! per instruction.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use core::asm::assemble;
+use core::{Chip8, Quirks};
+
+/// Arithmetic and jumps only, never touching the display.
+const ALU_LOOP: &str = "
+loop:
+    ADD V0, 1
+    ADD V1, V0
+    XOR V2, V1
+    SHR V2
+    SUB V3, V0
+    OR V4, V3
+    AND V5, V4
+    SE V0, 0
+    JP loop
+    LD V6, 0
+    JP loop
+";
+
+/// A sprite drawn all over the screen like most games do every frame.
+const DRAW_LOOP: &str = "
+loop:
+    LD F, V0
+    DRW V0, V1, 5
+    ADD V0, 5
+    ADD V1, 3
+    JP loop
+";
+
+/// SUPER-CHIP 16x16 sprites, drawn as two 8 pixel wide columns.
+const WIDE_DRAW_LOOP: &str = "
+    HIGH
+loop:
+    DRW V0, V1, 0
+    ADD V0, 16
+    ADD V1, 3
+    JP loop
+";
+
+fn chip8(source: &str, quirks: Quirks) -> Chip8 {
+    let mut chip8 = Chip8::builder().quirks(quirks).build();
+    chip8.load_rom_bytes(&assemble(source).unwrap()).unwrap();
+    chip8
+}
+
+fn bench_loop(c: &mut Criterion, name: &str, source: &str, quirks: Quirks) {
+    let mut chip8 = chip8(source, quirks);
+    c.bench_function(name, |b| b.iter(|| chip8.tick().unwrap()));
+}
+
+fn cpu(c: &mut Criterion) {
+    bench_loop(c, "alu loop", ALU_LOOP, Quirks::default());
+    bench_loop(c, "draw loop", DRAW_LOOP, Quirks::default());
+    bench_loop(c, "wide draw loop", WIDE_DRAW_LOOP, Quirks::schip());
+}
+
+criterion_group!(benches, cpu);
+criterion_main!(benches);
@@ -0,0 +1,33 @@
+//! Drive the emulator one frame at a time without `Chip8::run`, the way a
+//! browser build would from `requestAnimationFrame`.
+
+use core::Chip8;
+
+const FPS: u32 = 60;
+
+/// What a `requestAnimationFrame` callback would do: run the instructions
+/// due in one frame, then move the timers forward by a frame.
+fn frame(chip8: &mut Chip8) -> bool {
+    for _ in 0..chip8.cpu.clock_hz / FPS {
+        if let Err(halt) = chip8.tick() {
+            println!("{}", halt);
+            return false;
+        }
+    }
+    chip8.tick_timers();
+    true
+}
+
+fn main() {
+    let mut chip8 = Chip8::builder().manual_timers().build();
+    chip8
+        .load_rom_bytes(include_bytes!("../../roms/MAZE"))
+        .unwrap();
+
+    for _ in 0..FPS * 10 {
+        if !frame(&mut chip8) {
+            break;
+        }
+    }
+    println!("executed {} instructions", chip8.cpu.cycle_count());
+}
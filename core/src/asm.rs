@@ -0,0 +1,385 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::Instruction::{self, *};
+
+/// Address where assembled programs are loaded.
+const BASE: u16 = 0x200;
+
+/// Error while assembling, with the 1-based line it happened on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+/// Operand of an instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operand {
+    V(u8),
+    I,
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+    Num(u16),
+    /// `LONG addr` of XO-CHIP `F000 nnnn`.
+    Long(u16),
+}
+
+use self::Operand::*;
+
+/// Assemble Chip8 source into a program loaded at 0x200.
+///
+/// The syntax is one instruction per line with the mnemonics printed by
+/// the disassembler. `name:` defines a label usable as an address, `.byte`,
+/// `.db` or `DB` emits raw bytes e.g. for sprites, and `;` starts a comment.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    // First pass: strip labels and comments, remembering label addresses.
+    let mut labels = HashMap::new();
+    let mut lines = Vec::new();
+    let mut addr = BASE;
+    for (n, line) in source.lines().enumerate() {
+        let n = n + 1;
+        let mut line = line.split(';').next().unwrap_or("").trim();
+        if let Some(pos) = line.find(':') {
+            let label = line[..pos].trim();
+            if !is_label(label) {
+                return Err(error(n, format!("invalid label `{}`", label)));
+            }
+            if labels.insert(label.to_string(), addr).is_some() {
+                return Err(error(n, format!("duplicate label `{}`", label)));
+            }
+            line = line[pos + 1..].trim();
+        }
+        if line.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, args) = match line.find(char::is_whitespace) {
+            Some(pos) => (&line[..pos], line[pos..].trim()),
+            None => (line, ""),
+        };
+        let mnemonic = mnemonic.to_ascii_uppercase();
+        let args: Vec<&str> = if args.is_empty() {
+            Vec::new()
+        } else {
+            args.split(',').map(str::trim).collect()
+        };
+        let size = if is_bytes(&mnemonic) {
+            args.len()
+        } else if args.iter().any(|arg| is_long(arg)) {
+            4
+        } else {
+            2
+        };
+        addr = addr.wrapping_add(size as u16);
+        lines.push((n, mnemonic, args));
+    }
+
+    // Second pass: encode with every label known.
+    let mut out = Vec::new();
+    for (n, mnemonic, args) in lines {
+        let ops = args
+            .iter()
+            .map(|arg| operand(arg, &labels))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| error(n, e))?;
+        if is_bytes(&mnemonic) {
+            for op in ops {
+                match op {
+                    Num(b) => out.push(byte(b).map_err(|e| error(n, e))?),
+                    _ => return Err(error(n, format!("{} takes only numbers", mnemonic))),
+                }
+            }
+            continue;
+        }
+        let ins = instruction(&mnemonic, &ops).map_err(|e| error(n, e))?;
+        out.extend(ins.to_bytes());
+    }
+
+    Ok(out)
+}
+
+fn error(line: usize, msg: String) -> AsmError {
+    AsmError { line, msg }
+}
+
+fn is_label(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+/// Directive emitting its operands as raw bytes, `.db` being the one
+/// printed by the disassembler.
+fn is_bytes(mnemonic: &str) -> bool {
+    matches!(mnemonic, ".BYTE" | ".DB" | "DB")
+}
+
+fn is_long(s: &str) -> bool {
+    s.to_ascii_uppercase().starts_with("LONG ")
+}
+
+fn operand(s: &str, labels: &HashMap<String, u16>) -> Result<Operand, String> {
+    let up = s.to_ascii_uppercase();
+    let op = match up.as_str() {
+        _ if is_long(s) => match operand(s[5..].trim(), labels)? {
+            Num(n) => Long(n),
+            _ => return Err(format!("LONG takes an address, got `{}`", s)),
+        },
+        "I" => I,
+        "[I]" => IndirectI,
+        "DT" => Dt,
+        "ST" => St,
+        "K" => K,
+        "F" => F,
+        "HF" => Hf,
+        "B" => B,
+        "R" => R,
+        _ if up.len() == 2 && up.starts_with('V') && up.as_bytes()[1].is_ascii_hexdigit() => {
+            V(u8::from_str_radix(&up[1..], 16).unwrap())
+        }
+        _ if up.starts_with("0X") => {
+            Num(u16::from_str_radix(&up[2..], 16).map_err(|_| format!("invalid number `{}`", s))?)
+        }
+        _ if up.starts_with("0B") => {
+            Num(u16::from_str_radix(&up[2..], 2).map_err(|_| format!("invalid number `{}`", s))?)
+        }
+        _ if up.starts_with(|c: char| c.is_ascii_digit()) => {
+            Num(up.parse().map_err(|_| format!("invalid number `{}`", s))?)
+        }
+        _ => match labels.get(s) {
+            Some(addr) => Num(*addr),
+            None => return Err(format!("unknown label `{}`", s)),
+        },
+    };
+    Ok(op)
+}
+
+fn addr(n: u16) -> Result<u16, String> {
+    if n <= 0xFFF {
+        Ok(n)
+    } else {
+        Err(format!("address {:#X} out of range", n))
+    }
+}
+
+fn byte(n: u16) -> Result<u8, String> {
+    if n <= 0xFF {
+        Ok(n as u8)
+    } else {
+        Err(format!("byte {:#X} out of range", n))
+    }
+}
+
+fn nibble(n: u16) -> Result<u8, String> {
+    if n <= 0xF {
+        Ok(n as u8)
+    } else {
+        Err(format!("nibble {:#X} out of range", n))
+    }
+}
+
+fn instruction(mnemonic: &str, ops: &[Operand]) -> Result<Instruction, String> {
+    let ins = match (mnemonic, ops) {
+        ("CLS", []) => Cls,
+        ("RET", []) => Ret,
+        ("SCD", [Num(n)]) => Scd(nibble(*n)?),
+        ("SCR", []) => Scr,
+        ("SCL", []) => Scl,
+        ("EXIT", []) => Exit,
+        ("LOW", []) => Low,
+        ("HIGH", []) => High,
+        ("SYS", [Num(a)]) => Sys(addr(*a)?),
+        ("JP", [Num(a)]) => Jp(addr(*a)?),
+        ("JP", [V(0), Num(a)]) => JpV0(addr(*a)?),
+        ("CALL", [Num(a)]) => Call(addr(*a)?),
+        ("SE", [V(x), Num(k)]) => SeVxByte(*x, byte(*k)?),
+        ("SE", [V(x), V(y)]) => SeVxVy(*x, *y),
+        ("SNE", [V(x), Num(k)]) => SneVxByte(*x, byte(*k)?),
+        ("SNE", [V(x), V(y)]) => SneVxVy(*x, *y),
+        ("LD", [V(x), Num(k)]) => LdVxByte(*x, byte(*k)?),
+        ("LD", [V(x), V(y)]) => LdVxVy(*x, *y),
+        ("LD", [V(x), Dt]) => LdVxDt(*x),
+        ("LD", [V(x), K]) => LdVxK(*x),
+        ("LD", [V(x), IndirectI]) => LdVxI(*x),
+        ("LD", [I, Num(a)]) => LdI(addr(*a)?),
+        ("LD", [I, Long(a)]) => LdILong(*a),
+        ("LD", [Dt, V(x)]) => LdDtVx(*x),
+        ("LD", [St, V(x)]) => LdStVx(*x),
+        ("LD", [F, V(x)]) => LdFVx(*x),
+        ("LD", [Hf, V(x)]) => LdHfVx(*x),
+        ("LD", [B, V(x)]) => LdBVx(*x),
+        ("LD", [IndirectI, V(x)]) => LdIVx(*x),
+        ("LD", [R, V(x)]) => LdRVx(*x),
+        ("LD", [V(x), R]) => LdVxR(*x),
+        ("ADD", [V(x), Num(k)]) => AddVxByte(*x, byte(*k)?),
+        ("ADD", [V(x), V(y)]) => AddVxVy(*x, *y),
+        ("ADD", [I, V(x)]) => AddIVx(*x),
+        ("OR", [V(x), V(y)]) => OrVxVy(*x, *y),
+        ("AND", [V(x), V(y)]) => AndVxVy(*x, *y),
+        ("XOR", [V(x), V(y)]) => XorVxVy(*x, *y),
+        ("SUB", [V(x), V(y)]) => SubVxVy(*x, *y),
+        ("SUBN", [V(x), V(y)]) => SubnVxVy(*x, *y),
+        ("SHR", [V(x)]) => ShrVxVy(*x, *x),
+        ("SHR", [V(x), V(y)]) => ShrVxVy(*x, *y),
+        ("SHL", [V(x)]) => ShlVxVy(*x, *x),
+        ("SHL", [V(x), V(y)]) => ShlVxVy(*x, *y),
+        ("RND", [V(x), Num(k)]) => RndVxByte(*x, byte(*k)?),
+        ("DRW", [V(x), V(y), Num(n)]) => Drw(*x, *y, nibble(*n)?),
+        ("SKP", [V(x)]) => SkpVx(*x),
+        ("SKNP", [V(x)]) => SknpVx(*x),
+        ("PLANE", [Num(n)]) => Plane(nibble(*n)?),
+        ("AUDIO", []) => LdPatternI,
+        (
+            "CLS" | "RET" | "SCD" | "SCR" | "SCL" | "EXIT" | "LOW" | "HIGH" | "SYS" | "JP" | "CALL"
+            | "SE" | "SNE" | "LD" | "ADD" | "OR" | "AND" | "XOR" | "SUB" | "SUBN" | "SHR" | "SHL"
+            | "RND" | "DRW" | "SKP" | "SKNP" | "PLANE" | "AUDIO",
+            _,
+        ) => return Err(format!("invalid operands for {}", mnemonic)),
+        _ => return Err(format!("unknown mnemonic `{}`", mnemonic)),
+    };
+    Ok(ins)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::disassemble;
+
+    /// Source of `rom` as printed by the disassembler.
+    fn listing(rom: &[u8]) -> String {
+        disassemble(rom, BASE)
+            .into_iter()
+            .map(|(_, _, text)| text + "\n")
+            .collect()
+    }
+
+    #[test]
+    fn instructions() {
+        let source = "
+            CLS
+            LD V3, 0x1F
+            DRW V0, V1, 5
+            ld i, 0x300 ; lowercase with a comment
+            LD [I], VF
+            SHR V2
+        ";
+        let rom = assemble(source).unwrap();
+        assert_eq!(
+            rom,
+            vec![0x00, 0xE0, 0x63, 0x1F, 0xD0, 0x15, 0xA3, 0x00, 0xFF, 0x55, 0x82, 0x26]
+        );
+    }
+
+    #[test]
+    fn labels() {
+        let source = "
+            start: CALL sub
+                   JP start
+            sub:   RET
+        ";
+        assert_eq!(
+            assemble(source),
+            Ok(vec![0x22, 0x04, 0x12, 0x00, 0x00, 0xEE])
+        );
+    }
+
+    #[test]
+    fn bytes_after_code() {
+        let source = "
+            LD I, sprite
+            DRW V0, V0, 2
+            sprite: .byte 0x80, 0b01000000
+        ";
+        assert_eq!(
+            assemble(source),
+            Ok(vec![0xA2, 0x04, 0xD0, 0x02, 0x80, 0x40])
+        );
+    }
+
+    #[test]
+    fn errors_have_line_numbers() {
+        let err = |source| assemble(source).unwrap_err();
+        assert_eq!(
+            err("CLS\nJP nowhere"),
+            error(2, "unknown label `nowhere`".into())
+        );
+        assert_eq!(err("\n\nFOO V0"), error(3, "unknown mnemonic `FOO`".into()));
+        assert_eq!(
+            err("LD V0, 0x100"),
+            error(1, "byte 0x100 out of range".into())
+        );
+        assert_eq!(err("ADD V0"), error(1, "invalid operands for ADD".into()));
+        assert_eq!(
+            err("first: CLS\nfirst: CLS"),
+            error(2, "duplicate label `first`".into())
+        );
+        assert_eq!(
+            err("CLS\nJP nowhere").to_string(),
+            "line 2: unknown label `nowhere`"
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let source = "
+            loop: LD V0, 0x05
+                  LD F, V0
+                  DRW V1, V2, 5
+                  SE V0, V1
+                  HIGH
+                  JP V0, loop
+        ";
+        let rom = assemble(source).unwrap();
+        assert_eq!(assemble(&listing(&rom)), Ok(rom));
+    }
+
+    #[test]
+    fn long_operand_moves_labels() {
+        let source = "
+            LD I, LONG sprite
+            sprite: .byte 0x80
+        ";
+        assert_eq!(assemble(source), Ok(vec![0xF0, 0x00, 0x02, 0x04, 0x80]));
+        assert_eq!(
+            assemble("LD V0, LONG 0x200").unwrap_err(),
+            error(1, "invalid operands for LD".into())
+        );
+    }
+
+    #[test]
+    fn db_directive() {
+        assert_eq!(
+            assemble(".db 0xAB, 0xCD\nDB 1\n.byte 2"),
+            Ok(vec![0xAB, 0xCD, 1, 2])
+        );
+        assert_eq!(assemble(".DB 0xAB"), Ok(vec![0xAB]));
+    }
+
+    #[test]
+    fn roms_round_trip() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/../roms");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let rom = std::fs::read(&path).unwrap();
+            let source = listing(&rom);
+            assert_eq!(assemble(&source), Ok(rom), "{}", path.display());
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use crate::Instruction;
+
+/// Disassemble `rom` placed at `base` into `(address, opcode, mnemonic)`.
+///
+/// Words which don't decode, and a trailing odd byte, are rendered as `.db`.
+pub fn disassemble(rom: &[u8], base: u16) -> Vec<(u16, u16, String)> {
+    let mut lines = Vec::new();
+    let mut pos = 0;
+    while pos < rom.len() {
+        let addr = base.wrapping_add(pos as u16);
+        let code = &rom[pos..];
+        let (opcode, text, size) = match (Instruction::fetch(code), code) {
+            (Some(ins), _) => (ins.encode(), ins.to_string(), ins.size() as usize),
+            (None, &[hi, lo, ..]) => {
+                let opcode = ((hi as u16) << 8) + lo as u16;
+                (opcode, format!(".db {:#04X}, {:#04X}", hi, lo), 2)
+            }
+            (None, &[b]) => (b as u16, format!(".db {:#04X}", b), 1),
+            (None, &[]) => unreachable!(),
+        };
+        lines.push((addr, opcode, text));
+        pos += size;
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(rom: &[u8], base: u16) -> String {
+        disassemble(rom, base)
+            .into_iter()
+            .map(|(addr, opcode, text)| format!("{:03X} {:04X} {}\n", addr, opcode, text))
+            .collect()
+    }
+
+    #[test]
+    fn maze() {
+        let expected = "\
+200 A21E LD I, 0x21E
+202 C201 RND V2, 0x01
+204 3201 SE V2, 0x01
+206 A21A LD I, 0x21A
+208 D014 DRW V0, V1, 4
+20A 7004 ADD V0, 0x04
+20C 3040 SE V0, 0x40
+20E 1200 JP 0x200
+210 6000 LD V0, 0x00
+212 7104 ADD V1, 0x04
+214 3120 SE V1, 0x20
+216 1200 JP 0x200
+218 1218 JP 0x218
+21A 8040 LD V0, V4
+21C 2010 CALL 0x010
+21E 2040 CALL 0x040
+220 8010 LD V0, V1
+";
+        assert_eq!(listing(include_bytes!("../../roms/MAZE"), 0x200), expected);
+    }
+
+    #[test]
+    fn undecodable_words() {
+        let rom = [0x51, 0x21, 0xFF, 0xFF];
+        assert_eq!(
+            listing(&rom, 0x300),
+            "300 5121 .db 0x51, 0x21\n302 FFFF .db 0xFF, 0xFF\n"
+        );
+    }
+
+    #[test]
+    fn odd_length() {
+        assert_eq!(
+            listing(&[0x00, 0xE0, 0x12], 0x200),
+            "200 00E0 CLS\n202 0012 .db 0x12\n"
+        );
+        assert!(disassemble(&[], 0x200).is_empty());
+    }
+
+    #[test]
+    fn long_i_is_one_line() {
+        let rom = [0xF0, 0x00, 0x12, 0x34, 0x00, 0xE0];
+        assert_eq!(
+            listing(&rom, 0x300),
+            "300 F000 LD I, LONG 0x1234\n304 00E0 CLS\n"
+        );
+        // A truncated `F000 nnnn` is kept as data.
+        assert_eq!(
+            listing(&[0xF0, 0x00, 0x12], 0x200),
+            "200 F000 .db 0xF0, 0x00\n202 0012 .db 0x12\n"
+        );
+    }
+}
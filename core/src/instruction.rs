@@ -0,0 +1,466 @@
+use std::fmt;
+
+/// Decoded Chip8 instruction.
+///
+/// `x` and `y` are register indices, `kk` is a byte and `nnn` is an address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// 0nnn - SYS addr
+    Sys(u16),
+    /// 00E0 - CLS
+    Cls,
+    /// 00EE - RET
+    Ret,
+    /// 00Cn - SCD nibble (SUPER-CHIP)
+    Scd(u8),
+    /// 00FD - EXIT (SUPER-CHIP)
+    Exit,
+    /// 00FB - SCR (SUPER-CHIP)
+    Scr,
+    /// 00FC - SCL (SUPER-CHIP)
+    Scl,
+    /// 00FE - LOW (SUPER-CHIP)
+    Low,
+    /// 00FF - HIGH (SUPER-CHIP)
+    High,
+    /// 1nnn - JP addr
+    Jp(u16),
+    /// 2nnn - CALL addr
+    Call(u16),
+    /// 3xkk - SE Vx, byte
+    SeVxByte(u8, u8),
+    /// 4xkk - SNE Vx, byte
+    SneVxByte(u8, u8),
+    /// 5xy0 - SE Vx, Vy
+    SeVxVy(u8, u8),
+    /// 6xkk - LD Vx, byte
+    LdVxByte(u8, u8),
+    /// 7xkk - ADD Vx, byte
+    AddVxByte(u8, u8),
+    /// 8xy0 - LD Vx, Vy
+    LdVxVy(u8, u8),
+    /// 8xy1 - OR Vx, Vy
+    OrVxVy(u8, u8),
+    /// 8xy2 - AND Vx, Vy
+    AndVxVy(u8, u8),
+    /// 8xy3 - XOR Vx, Vy
+    XorVxVy(u8, u8),
+    /// 8xy4 - ADD Vx, Vy
+    AddVxVy(u8, u8),
+    /// 8xy5 - SUB Vx, Vy
+    SubVxVy(u8, u8),
+    /// 8xy6 - SHR Vx, Vy
+    ShrVxVy(u8, u8),
+    /// 8xy7 - SUBN Vx, Vy
+    SubnVxVy(u8, u8),
+    /// 8xyE - SHL Vx, Vy
+    ShlVxVy(u8, u8),
+    /// 9xy0 - SNE Vx, Vy
+    SneVxVy(u8, u8),
+    /// Annn - LD I, addr
+    LdI(u16),
+    /// Bnnn - JP V0, addr
+    JpV0(u16),
+    /// Cxkk - RND Vx, byte
+    RndVxByte(u8, u8),
+    /// Dxyn - DRW Vx, Vy, nibble
+    Drw(u8, u8, u8),
+    /// Ex9E - SKP Vx
+    SkpVx(u8),
+    /// ExA1 - SKNP Vx
+    SknpVx(u8),
+    /// F000 nnnn - LD I, LONG addr (XO-CHIP)
+    LdILong(u16),
+    /// Fn01 - PLANE n (XO-CHIP)
+    Plane(u8),
+    /// F002 - AUDIO (XO-CHIP)
+    LdPatternI,
+    /// Fx07 - LD Vx, DT
+    LdVxDt(u8),
+    /// Fx0A - LD Vx, K
+    LdVxK(u8),
+    /// Fx15 - LD DT, Vx
+    LdDtVx(u8),
+    /// Fx18 - LD ST, Vx
+    LdStVx(u8),
+    /// Fx1E - ADD I, Vx
+    AddIVx(u8),
+    /// Fx29 - LD F, Vx
+    LdFVx(u8),
+    /// Fx30 - LD HF, Vx (SUPER-CHIP)
+    LdHfVx(u8),
+    /// Fx33 - LD B, Vx
+    LdBVx(u8),
+    /// Fx55 - LD [I], Vx
+    LdIVx(u8),
+    /// Fx65 - LD Vx, [I]
+    LdVxI(u8),
+    /// Fx75 - LD R, Vx (SUPER-CHIP)
+    LdRVx(u8),
+    /// Fx85 - LD Vx, R (SUPER-CHIP)
+    LdVxR(u8),
+}
+
+use self::Instruction::*;
+
+impl Instruction {
+    /// Decode the instruction at the start of `code`, reading the operand
+    /// word as well for the 4 byte XO-CHIP `F000 nnnn`.
+    pub fn fetch(code: &[u8]) -> Option<Instruction> {
+        match *code {
+            [0xF0, 0x00, hi, lo, ..] => Some(LdILong(((hi as u16) << 8) + lo as u16)),
+            [hi, lo, ..] => Instruction::decode(hi, lo),
+            _ => None,
+        }
+    }
+
+    /// Decode an instruction from its high and low bytes.
+    /// `F000` needs the following word too, so it's only decoded by `fetch`.
+    pub fn decode(hi: u8, lo: u8) -> Option<Instruction> {
+        let nnn = (((hi & 0xf) as u16) << 8) + lo as u16;
+        let kk = lo;
+        let ins = match (hi >> 4, hi & 0xf, lo >> 4, lo & 0xf) {
+            (0x0, 0x0, 0xE, 0x0) => Cls,
+            (0x0, 0x0, 0xE, 0xE) => Ret,
+            (0x0, 0x0, 0xC, n) => Scd(n),
+            (0x0, 0x0, 0xF, 0xB) => Scr,
+            (0x0, 0x0, 0xF, 0xD) => Exit,
+            (0x0, 0x0, 0xF, 0xC) => Scl,
+            (0x0, 0x0, 0xF, 0xE) => Low,
+            (0x0, 0x0, 0xF, 0xF) => High,
+            (0x0, _, _, _) => Sys(nnn),
+            (0x1, _, _, _) => Jp(nnn),
+            (0x2, _, _, _) => Call(nnn),
+            (0x3, x, _, _) => SeVxByte(x, kk),
+            (0x4, x, _, _) => SneVxByte(x, kk),
+            (0x5, x, y, 0x0) => SeVxVy(x, y),
+            (0x6, x, _, _) => LdVxByte(x, kk),
+            (0x7, x, _, _) => AddVxByte(x, kk),
+            (0x8, x, y, 0x0) => LdVxVy(x, y),
+            (0x8, x, y, 0x1) => OrVxVy(x, y),
+            (0x8, x, y, 0x2) => AndVxVy(x, y),
+            (0x8, x, y, 0x3) => XorVxVy(x, y),
+            (0x8, x, y, 0x4) => AddVxVy(x, y),
+            (0x8, x, y, 0x5) => SubVxVy(x, y),
+            (0x8, x, y, 0x6) => ShrVxVy(x, y),
+            (0x8, x, y, 0x7) => SubnVxVy(x, y),
+            (0x8, x, y, 0xE) => ShlVxVy(x, y),
+            (0x9, x, y, 0x0) => SneVxVy(x, y),
+            (0xA, _, _, _) => LdI(nnn),
+            (0xB, _, _, _) => JpV0(nnn),
+            (0xC, x, _, _) => RndVxByte(x, kk),
+            (0xD, x, y, n) => Drw(x, y, n),
+            (0xE, x, 0x9, 0xE) => SkpVx(x),
+            (0xE, x, 0xA, 0x1) => SknpVx(x),
+            (0xF, n, 0x0, 0x1) => Plane(n),
+            (0xF, 0x0, 0x0, 0x2) => LdPatternI,
+            (0xF, x, 0x0, 0x7) => LdVxDt(x),
+            (0xF, x, 0x0, 0xA) => LdVxK(x),
+            (0xF, x, 0x1, 0x5) => LdDtVx(x),
+            (0xF, x, 0x1, 0x8) => LdStVx(x),
+            (0xF, x, 0x1, 0xE) => AddIVx(x),
+            (0xF, x, 0x2, 0x9) => LdFVx(x),
+            (0xF, x, 0x3, 0x0) => LdHfVx(x),
+            (0xF, x, 0x3, 0x3) => LdBVx(x),
+            (0xF, x, 0x5, 0x5) => LdIVx(x),
+            (0xF, x, 0x6, 0x5) => LdVxI(x),
+            (0xF, x, 0x7, 0x5) => LdRVx(x),
+            (0xF, x, 0x8, 0x5) => LdVxR(x),
+            _ => return None,
+        };
+        Some(ins)
+    }
+
+    /// Size of the instruction in bytes.
+    pub fn size(&self) -> u16 {
+        match self {
+            LdILong(_) => 4,
+            _ => 2,
+        }
+    }
+
+    /// Encode the instruction into bytes in memory order.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let op = self.encode();
+        let mut bytes = vec![(op >> 8) as u8, op as u8];
+        if let LdILong(nnnn) = *self {
+            bytes.extend_from_slice(&[(nnnn >> 8) as u8, nnnn as u8]);
+        }
+        bytes
+    }
+
+    /// Encode the instruction back into its opcode, which is only the
+    /// first word of `F000 nnnn`.
+    pub fn encode(&self) -> u16 {
+        let x_ = |op: u16, x: u8| op | (x as u16) << 8;
+        let xy = |op: u16, x: u8, y: u8| op | (x as u16) << 8 | (y as u16) << 4;
+        match *self {
+            Sys(nnn) => nnn,
+            Cls => 0x00E0,
+            Ret => 0x00EE,
+            Scd(n) => 0x00C0 | n as u16,
+            Scr => 0x00FB,
+            Exit => 0x00FD,
+            Scl => 0x00FC,
+            Low => 0x00FE,
+            High => 0x00FF,
+            Jp(nnn) => 0x1000 | nnn,
+            Call(nnn) => 0x2000 | nnn,
+            SeVxByte(x, kk) => x_(0x3000, x) | kk as u16,
+            SneVxByte(x, kk) => x_(0x4000, x) | kk as u16,
+            SeVxVy(x, y) => xy(0x5000, x, y),
+            LdVxByte(x, kk) => x_(0x6000, x) | kk as u16,
+            AddVxByte(x, kk) => x_(0x7000, x) | kk as u16,
+            LdVxVy(x, y) => xy(0x8000, x, y),
+            OrVxVy(x, y) => xy(0x8001, x, y),
+            AndVxVy(x, y) => xy(0x8002, x, y),
+            XorVxVy(x, y) => xy(0x8003, x, y),
+            AddVxVy(x, y) => xy(0x8004, x, y),
+            SubVxVy(x, y) => xy(0x8005, x, y),
+            ShrVxVy(x, y) => xy(0x8006, x, y),
+            SubnVxVy(x, y) => xy(0x8007, x, y),
+            ShlVxVy(x, y) => xy(0x800E, x, y),
+            SneVxVy(x, y) => xy(0x9000, x, y),
+            LdI(nnn) => 0xA000 | nnn,
+            JpV0(nnn) => 0xB000 | nnn,
+            RndVxByte(x, kk) => x_(0xC000, x) | kk as u16,
+            Drw(x, y, n) => xy(0xD000, x, y) | n as u16,
+            SkpVx(x) => x_(0xE09E, x),
+            SknpVx(x) => x_(0xE0A1, x),
+            LdILong(_) => 0xF000,
+            Plane(n) => x_(0xF001, n),
+            LdPatternI => 0xF002,
+            LdVxDt(x) => x_(0xF007, x),
+            LdVxK(x) => x_(0xF00A, x),
+            LdDtVx(x) => x_(0xF015, x),
+            LdStVx(x) => x_(0xF018, x),
+            AddIVx(x) => x_(0xF01E, x),
+            LdFVx(x) => x_(0xF029, x),
+            LdHfVx(x) => x_(0xF030, x),
+            LdBVx(x) => x_(0xF033, x),
+            LdIVx(x) => x_(0xF055, x),
+            LdVxI(x) => x_(0xF065, x),
+            LdRVx(x) => x_(0xF075, x),
+            LdVxR(x) => x_(0xF085, x),
+        }
+    }
+
+    /// Opcode pattern of the instruction such as `8xy4`, the same for
+    /// every operand.
+    pub fn pattern(&self) -> &'static str {
+        match self {
+            Sys(_) => "0nnn",
+            Cls => "00E0",
+            Ret => "00EE",
+            Scd(_) => "00Cn",
+            Scr => "00FB",
+            Exit => "00FD",
+            Scl => "00FC",
+            Low => "00FE",
+            High => "00FF",
+            Jp(_) => "1nnn",
+            Call(_) => "2nnn",
+            SeVxByte(..) => "3xkk",
+            SneVxByte(..) => "4xkk",
+            SeVxVy(..) => "5xy0",
+            LdVxByte(..) => "6xkk",
+            AddVxByte(..) => "7xkk",
+            LdVxVy(..) => "8xy0",
+            OrVxVy(..) => "8xy1",
+            AndVxVy(..) => "8xy2",
+            XorVxVy(..) => "8xy3",
+            AddVxVy(..) => "8xy4",
+            SubVxVy(..) => "8xy5",
+            ShrVxVy(..) => "8xy6",
+            SubnVxVy(..) => "8xy7",
+            ShlVxVy(..) => "8xyE",
+            SneVxVy(..) => "9xy0",
+            LdI(_) => "Annn",
+            JpV0(_) => "Bnnn",
+            RndVxByte(..) => "Cxkk",
+            Drw(..) => "Dxyn",
+            SkpVx(_) => "Ex9E",
+            SknpVx(_) => "ExA1",
+            LdILong(_) => "F000",
+            Plane(_) => "Fn01",
+            LdPatternI => "F002",
+            LdVxDt(_) => "Fx07",
+            LdVxK(_) => "Fx0A",
+            LdDtVx(_) => "Fx15",
+            LdStVx(_) => "Fx18",
+            AddIVx(_) => "Fx1E",
+            LdFVx(_) => "Fx29",
+            LdHfVx(_) => "Fx30",
+            LdBVx(_) => "Fx33",
+            LdIVx(_) => "Fx55",
+            LdVxI(_) => "Fx65",
+            LdRVx(_) => "Fx75",
+            LdVxR(_) => "Fx85",
+        }
+    }
+
+    /// Whether the instruction exists only in SUPER-CHIP.
+    pub fn is_schip(&self) -> bool {
+        matches!(
+            self,
+            Scd(_) | Scr | Scl | Exit | Low | High | LdHfVx(_) | LdRVx(_) | LdVxR(_)
+        )
+    }
+
+    /// Whether the instruction exists only in XO-CHIP.
+    pub fn is_xochip(&self) -> bool {
+        matches!(self, LdILong(_) | Plane(_) | LdPatternI)
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Sys(nnn) => write!(f, "SYS {:#05X}", nnn),
+            Cls => write!(f, "CLS"),
+            Ret => write!(f, "RET"),
+            Scd(n) => write!(f, "SCD {}", n),
+            Scr => write!(f, "SCR"),
+            Exit => write!(f, "EXIT"),
+            Scl => write!(f, "SCL"),
+            Low => write!(f, "LOW"),
+            High => write!(f, "HIGH"),
+            Jp(nnn) => write!(f, "JP {:#05X}", nnn),
+            Call(nnn) => write!(f, "CALL {:#05X}", nnn),
+            SeVxByte(x, kk) => write!(f, "SE V{:X}, {:#04X}", x, kk),
+            SneVxByte(x, kk) => write!(f, "SNE V{:X}, {:#04X}", x, kk),
+            SeVxVy(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            LdVxByte(x, kk) => write!(f, "LD V{:X}, {:#04X}", x, kk),
+            AddVxByte(x, kk) => write!(f, "ADD V{:X}, {:#04X}", x, kk),
+            LdVxVy(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            OrVxVy(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            AndVxVy(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            XorVxVy(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            AddVxVy(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            SubVxVy(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            ShrVxVy(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            SubnVxVy(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            ShlVxVy(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            SneVxVy(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            LdI(nnn) => write!(f, "LD I, {:#05X}", nnn),
+            JpV0(nnn) => write!(f, "JP V0, {:#05X}", nnn),
+            RndVxByte(x, kk) => write!(f, "RND V{:X}, {:#04X}", x, kk),
+            Drw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            SkpVx(x) => write!(f, "SKP V{:X}", x),
+            SknpVx(x) => write!(f, "SKNP V{:X}", x),
+            LdILong(nnnn) => write!(f, "LD I, LONG {:#06X}", nnnn),
+            Plane(n) => write!(f, "PLANE {}", n),
+            LdPatternI => write!(f, "AUDIO"),
+            LdVxDt(x) => write!(f, "LD V{:X}, DT", x),
+            LdVxK(x) => write!(f, "LD V{:X}, K", x),
+            LdDtVx(x) => write!(f, "LD DT, V{:X}", x),
+            LdStVx(x) => write!(f, "LD ST, V{:X}", x),
+            AddIVx(x) => write!(f, "ADD I, V{:X}", x),
+            LdFVx(x) => write!(f, "LD F, V{:X}", x),
+            LdHfVx(x) => write!(f, "LD HF, V{:X}", x),
+            LdBVx(x) => write!(f, "LD B, V{:X}", x),
+            LdIVx(x) => write!(f, "LD [I], V{:X}", x),
+            LdVxI(x) => write!(f, "LD V{:X}, [I]", x),
+            LdRVx(x) => write!(f, "LD R, V{:X}", x),
+            LdVxR(x) => write!(f, "LD V{:X}, R", x),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(op: u16) -> Option<Instruction> {
+        Instruction::decode((op >> 8) as u8, op as u8)
+    }
+
+    #[test]
+    fn decode_families() {
+        let cases = [
+            (0x00E0, Cls),
+            (0x00EE, Ret),
+            (0x0123, Sys(0x123)),
+            (0x00C4, Scd(4)),
+            (0x00FB, Scr),
+            (0x00FC, Scl),
+            (0x1ABC, Jp(0xABC)),
+            (0x2DEF, Call(0xDEF)),
+            (0x3A12, SeVxByte(0xA, 0x12)),
+            (0x4B34, SneVxByte(0xB, 0x34)),
+            (0x5120, SeVxVy(1, 2)),
+            (0x631F, LdVxByte(3, 0x1F)),
+            (0x7401, AddVxByte(4, 0x01)),
+            (0x8120, LdVxVy(1, 2)),
+            (0x8121, OrVxVy(1, 2)),
+            (0x8122, AndVxVy(1, 2)),
+            (0x8123, XorVxVy(1, 2)),
+            (0x8124, AddVxVy(1, 2)),
+            (0x8125, SubVxVy(1, 2)),
+            (0x8126, ShrVxVy(1, 2)),
+            (0x8127, SubnVxVy(1, 2)),
+            (0x812E, ShlVxVy(1, 2)),
+            (0x9120, SneVxVy(1, 2)),
+            (0xA300, LdI(0x300)),
+            (0xB200, JpV0(0x200)),
+            (0xC5FF, RndVxByte(5, 0xFF)),
+            (0xD015, Drw(0, 1, 5)),
+            (0xE69E, SkpVx(6)),
+            (0xE7A1, SknpVx(7)),
+            (0xF807, LdVxDt(8)),
+            (0xF90A, LdVxK(9)),
+            (0xFA15, LdDtVx(0xA)),
+            (0xFB18, LdStVx(0xB)),
+            (0xFC1E, AddIVx(0xC)),
+            (0xFD29, LdFVx(0xD)),
+            (0xFF33, LdBVx(0xF)),
+            (0xF055, LdIVx(0)),
+            (0xF165, LdVxI(1)),
+        ];
+        for &(op, ins) in cases.iter() {
+            assert_eq!(decode(op), Some(ins), "{:04X}", op);
+        }
+    }
+
+    #[test]
+    fn unknown_opcodes() {
+        for &op in [0x5121, 0x812F, 0x9121, 0xE000, 0xF0FF].iter() {
+            assert_eq!(decode(op), None, "{:04X}", op);
+        }
+    }
+
+    #[test]
+    fn mnemonics() {
+        assert_eq!(LdVxByte(3, 0x1F).to_string(), "LD V3, 0x1F");
+        assert_eq!(Jp(0x200).to_string(), "JP 0x200");
+        assert_eq!(Drw(0, 1, 5).to_string(), "DRW V0, V1, 5");
+        assert_eq!(LdIVx(0xF).to_string(), "LD [I], VF");
+        assert_eq!(Cls.to_string(), "CLS");
+    }
+
+    #[test]
+    fn encode_round_trips() {
+        for op in 0..=0xFFFFu16 {
+            if let Some(ins) = decode(op) {
+                assert_eq!(ins.encode(), op, "{}", ins);
+            }
+        }
+    }
+
+    #[test]
+    fn fetch_long() {
+        assert_eq!(
+            Instruction::fetch(&[0xF0, 0x00, 0x12, 0x34]),
+            Some(LdILong(0x1234))
+        );
+        assert_eq!(Instruction::fetch(&[0xF0, 0x00]), None);
+        assert_eq!(Instruction::fetch(&[0x63]), None);
+        assert_eq!(
+            Instruction::fetch(&[0x63, 0x1F, 0xF0]),
+            Some(LdVxByte(3, 0x1F))
+        );
+        assert_eq!(LdILong(0x1234).to_bytes(), vec![0xF0, 0x00, 0x12, 0x34]);
+        assert_eq!((LdILong(0x1234).size(), Cls.size()), (4, 2));
+        assert_eq!(LdILong(0x1234).to_string(), "LD I, LONG 0x1234");
+    }
+}
@@ -1,19 +1,67 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
 use std::convert::From;
-use std::io::Read;
-use std::sync::atomic::{AtomicU8, Ordering};
-use std::sync::{mpsc, Arc};
+use std::io::{Read, Write};
+#[cfg(not(target_arch = "wasm32"))]
+use std::ops::ControlFlow;
+use std::rc::Rc;
+use std::sync::mpsc;
+#[cfg(not(target_arch = "wasm32"))]
 use std::thread::sleep;
-use std::time::{Duration, Instant};
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
 
 use log::*;
 use rand::prelude::*;
 
-#[derive(Debug)]
-pub struct Error(pub String);
+pub mod asm;
+pub mod disasm;
+mod instruction;
+pub mod romdb;
+
+pub use self::instruction::Instruction;
+use self::Instruction::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// Reading or writing a stream failed.
+    Io(String),
+    /// `Display` failed to show the screen, e.g. writing to the terminal.
+    Display(String),
+    /// ROM doesn't fit in the `max` bytes of RAM after the start address.
+    /// `size` is how much of it was read, which stops at `max + 1` bytes
+    /// when loading from a stream.
+    RomTooLarge { size: usize, max: usize },
+    /// Address isn't in RAM.
+    AddressOutOfRange(usize),
+    /// 4x5 font at the address would overlap the big font or the program.
+    FontOverlaps(u16),
+}
 
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Error {
-        Error(e.to_string())
+        Error::Io(e.to_string())
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Display(e) => write!(f, "display failed: {}", e),
+            Error::RomTooLarge { size, max } => write!(
+                f,
+                "ROM of at least {} bytes is larger than the {} bytes available",
+                size, max
+            ),
+            Error::AddressOutOfRange(addr) => write!(f, "address {:#05X} is out of RAM", addr),
+            Error::FontOverlaps(base) => write!(
+                f,
+                "font at {:#05X} overlaps the big font or the program",
+                base
+            ),
+        }
     }
 }
 
@@ -21,578 +69,4569 @@ impl From<std::io::Error> for Error {
 pub struct Chip8 {
     pub cpu: Cpu,
     pub ram: Ram,
-    pub dsp: Box<Display>,
-    pub inp: mpsc::Receiver<Key>,
+    /// Screen drawn on by the CPU and presented on `dsp`.
+    pub fb: FrameBuffer,
+    pub dsp: Box<dyn Display>,
+    pub audio: Option<Box<dyn Audio>>,
+    pub inp: Box<dyn Input>,
+    /// Whether `audio` is currently playing.
+    beeping: bool,
+    /// XO-CHIP pattern last given to `audio`.
+    pattern: Option<[u8; 16]>,
+    /// Set while the frontend has paused the emulation.
+    paused: bool,
+    /// Clock of the timers moved only by `tick_timers`, if asked for by
+    /// `Chip8Builder::manual_timers`.
+    timer_clock: Option<Rc<ManualClock>>,
+    /// Number of `tick_timers` calls so far.
+    timer_ticks: u64,
 }
 
 impl Chip8 {
-    pub fn new(dsp: Box<Display>, inp: mpsc::Receiver<Key>) -> Self {
-        Chip8 {
-            cpu: Cpu::new(),
-            ram: Ram::new(),
-            dsp,
-            inp,
+    pub fn new(dsp: Box<dyn Display>, inp: Box<dyn Input>) -> Self {
+        Chip8Builder::new().display(dsp).input(inp).build()
+    }
+
+    /// Create emulator which remembers the last `depth` instructions
+    /// so that they can be undone by `step_back`.
+    pub fn with_history(dsp: Box<dyn Display>, inp: Box<dyn Input>, depth: usize) -> Self {
+        Chip8Builder::new()
+            .display(dsp)
+            .input(inp)
+            .history(depth)
+            .build()
+    }
+
+    pub fn builder() -> Chip8Builder {
+        Chip8Builder::new()
+    }
+
+    /// Create emulator which neither shows anything nor reads any key,
+    /// e.g. for benchmarks.
+    pub fn headless() -> Self {
+        Chip8::new(Box::new(NullDisplay), Box::new(NullInput))
+    }
+
+    /// Load `rom` into RAM, returning its size. See `Ram::load_bytes`.
+    pub fn load_rom_bytes(&mut self, rom: &[u8]) -> Result<usize, Error> {
+        self.ram.load_bytes(rom)
+    }
+
+    /// Quirks recommended for `rom` by the built-in database, if known.
+    pub fn detect_quirks(rom: &[u8]) -> Option<Quirks> {
+        romdb::lookup(rom)
+    }
+
+    /// Screen as a binary PBM image. See `pbm`.
+    pub fn screenshot(&self) -> Vec<u8> {
+        self.fb.to_pbm()
+    }
+
+    /// Size and hash of the loaded ROM, `None` before one is loaded.
+    pub fn rom_info(&self) -> Option<RomInfo> {
+        self.ram.rom_info().cloned()
+    }
+
+    /// Change the interpreter variations to follow. RAM keeps the size
+    /// it was built with, so XO-CHIP should be chosen by the builder.
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.cpu.quirks = quirks;
+    }
+
+    /// Run chip8 emulator at `cpu.clock_hz` until it halts.
+    /// Blocks the thread, so hosts like the browser should call `tick` instead.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn run(&mut self) -> Halt {
+        let start = Instant::now();
+        let mut executed: u64 = 0;
+        loop {
+            if let Err(halt) = self.tick() {
+                return halt;
+            }
+            executed += 1;
+
+            // Wait until the next instruction is due.
+            let due = Duration::from_nanos(executed * 1_000_000_000 / self.cpu.clock_hz as u64);
+            if let Some(remaining) = due.checked_sub(start.elapsed()) {
+                sleep(remaining);
+            }
         }
     }
 
-    /// Run chip8 emulator.
-    pub fn run(&mut self) {
-        self.cpu.run(&mut self.ram, &mut self.dsp, &mut self.inp)
+    /// Run `cpu_hz` instructions per second and call `per_frame` after the
+    /// instructions of each of `frame_rate` frames per second, e.g. to draw
+    /// and read input. The timers count down at 60Hz by themselves, or by
+    /// `tick_timers` called here with `Chip8Builder::manual_timers`.
+    ///
+    /// Returns once `per_frame` breaks or the program halts. Nothing is
+    /// executed while paused or after a halt, but frames keep coming, so
+    /// calling this again on a halted program only calls `per_frame`.
+    /// Panics if `frame_rate` is 0.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn run_at<F>(&mut self, cpu_hz: u32, frame_rate: u32, mut per_frame: F) -> Result<(), Halt>
+    where
+        F: FnMut(&mut Chip8) -> ControlFlow<()>,
+    {
+        assert!(frame_rate > 0, "frame_rate must be at least 1");
+        // Start in phase with the timers, so that at 60 frames per second
+        // each frame sees them count down by exactly one.
+        if self.timer_clock.is_none() {
+            sleep(self.cpu.time_until_next_tick());
+        }
+        let start = Instant::now();
+        let mut frames: u64 = 0;
+        let mut executed: u64 = 0;
+        let mut ticked: u64 = 0;
+        loop {
+            frames += 1;
+            let due = frames * cpu_hz as u64 / frame_rate as u64;
+            if self.paused || self.cpu.is_halted() {
+                // Don't catch up on the skipped frames after resuming.
+                executed = due;
+            }
+            while executed < due {
+                self.tick()?;
+                executed += 1;
+            }
+            if self.timer_clock.is_some() {
+                while ticked < frames * 60 / frame_rate as u64 {
+                    self.tick_timers();
+                    ticked += 1;
+                }
+            }
+
+            if per_frame(self).is_break() {
+                return Ok(());
+            }
+
+            // Sleep until the next frame counting from the start, so that
+            // oversleeping in one frame is made up in the next.
+            let next = Duration::from_nanos(frames * 1_000_000_000 / frame_rate as u64);
+            if let Some(remaining) = next.checked_sub(start.elapsed()) {
+                sleep(remaining);
+            }
+        }
     }
 
-    /// One tick of CPU.
-    pub fn tick(&mut self) {
-        self.cpu.tick(&mut self.ram, &mut self.dsp, &mut self.inp)
+    /// Time until the timers next count down. See `Cpu::time_until_next_tick`.
+    pub fn time_until_next_tick(&self) -> Duration {
+        self.cpu.time_until_next_tick()
     }
-}
 
-/// Trait to draw information to display device.
-pub trait Display {
-    fn draw(&self, x: u8, y: u8, data: Vec<u8>) -> Result<u8, ()>;
-    fn clear(&self);
-}
+    /// Count the timers down by one 60Hz tick. Only has an effect with
+    /// `Chip8Builder::manual_timers`, otherwise they follow their clock.
+    pub fn tick_timers(&mut self) {
+        if let Some(clock) = &self.timer_clock {
+            self.timer_ticks += 1;
+            // Rounded up so that every tick moves the timers by exactly one.
+            let at = Duration::from_nanos((self.timer_ticks * 1_000_000_000).div_ceil(60));
+            clock.advance(at.saturating_sub(clock.now()));
+        }
+        self.update_audio();
+    }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct Key(pub u8);
+    /// Execute exactly one instruction, never blocking.
+    pub fn tick(&mut self) -> Result<(), Halt> {
+        self.step().map(|_| ())
+    }
 
-impl std::convert::From<char> for Key {
-    fn from(c: char) -> Key {
-        match c {
-            '1' => Key(0x1),
-            '2' => Key(0x2),
-            '3' => Key(0x3),
-            'q' => Key(0x4),
-            'w' => Key(0x5),
-            'e' => Key(0x6),
-            'a' => Key(0x7),
-            's' => Key(0x8),
-            'd' => Key(0x9),
-            'z' => Key(0xA),
-            'x' => Key(0x0),
-            'c' => Key(0xB),
-            '4' => Key(0xC),
-            'r' => Key(0xD),
-            'f' => Key(0xE),
-            'v' => Key(0xF),
-            _ => Key(0x99),
+    /// Execute exactly one instruction, telling what it was.
+    pub fn step(&mut self) -> Result<Step, Halt> {
+        let res = self.fb.resolution();
+        let step = self.cpu.step(&mut self.ram, &mut self.fb, &mut self.inp);
+        self.update_audio();
+        let step = step?;
+        let shown = if self.fb.resolution() != res {
+            self.dsp.clear().and_then(|_| self.present())
+        } else {
+            self.present()
+        };
+        shown.map_err(|error| Halt::DisplayFailed {
+            pc: step.pc,
+            error,
+            backtrace: self.cpu.backtrace(),
+        })?;
+        Ok(step)
+    }
+
+    /// Present the screen on `dsp` if it changed since the last time.
+    fn present(&mut self) -> Result<(), Error> {
+        if self.fb.is_dirty() {
+            self.dsp.present(&self.fb)?;
+            self.fb.mark_clean();
         }
+        Ok(())
     }
-}
 
-#[derive(Debug)]
-pub struct Cpu {
-    /// 8bit general purpose Registers.
-    v: [u8; 16],
-    /// Index register.
-    i: u16,
-    /// Stack,
-    stack: [u16; 16],
-    /// Stack pointer.
-    sp: u16,
-    /// Program counter.
-    pub pc: u16,
-    /// Delay timer.
-    pub dt: DelayTimer,
-    /// Key being entered.
-    key: Option<Key>,
+    /// Play the tone while the sound timer is active.
+    fn update_audio(&mut self) {
+        let pattern = self.cpu.pattern();
+        if pattern != self.pattern {
+            if let (Some(audio), Some(pattern)) = (self.audio.as_mut(), pattern) {
+                audio.set_pattern(pattern, PATTERN_RATE);
+            }
+            self.pattern = pattern;
+        }
+
+        let on = !self.paused && self.cpu.st.get() > 0;
+        if on == self.beeping {
+            return;
+        }
+        if let Some(audio) = self.audio.as_mut() {
+            if on {
+                audio.play();
+            } else {
+                audio.pause();
+            }
+        }
+        self.beeping = on;
+    }
+
+    /// Stop the timers and the tone. Frontends stop calling `tick` until
+    /// `resume`.
+    pub fn pause(&mut self) {
+        self.paused = true;
+        self.cpu.dt.pause();
+        self.cpu.st.pause();
+        self.update_audio();
+    }
+
+    /// Continue from where `pause` stopped.
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.cpu.dt.resume();
+        self.cpu.st.resume();
+        self.update_audio();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// `pause` or `resume` depending on `paused`.
+    pub fn set_paused(&mut self, paused: bool) {
+        if paused {
+            self.pause();
+        } else {
+            self.resume();
+        }
+    }
+
+    /// Restart the loaded ROM as if it was just loaded, undoing any changes
+    /// the program made to itself.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.cpu.reset();
+        self.ram.reload();
+        self.fb = FrameBuffer::new();
+        self.update_audio();
+        self.dsp.clear()?;
+        self.present()
+    }
+
+    /// Undo the last instruction.
+    pub fn step_back(&mut self) -> bool {
+        self.cpu.step_back(&mut self.ram)
+    }
 }
 
-/// 60Hz Delay timer using thread.
-#[derive(Debug)]
-pub struct DelayTimer {
-    v: Arc<AtomicU8>,
-    th: Option<std::thread::JoinHandle<()>>,
+/// Builder to configure `Chip8` before constructing it.
+#[derive(Default)]
+pub struct Chip8Builder {
+    dsp: Option<Box<dyn Display>>,
+    audio: Option<Box<dyn Audio>>,
+    inp: Option<Box<dyn Input>>,
+    quirks: Quirks,
+    clock_hz: Option<u32>,
+    clock: Option<Rc<dyn Clock>>,
+    seed: Option<u64>,
+    start: Option<u16>,
+    history: usize,
+    trace: usize,
+    font: Font,
+    ram: Option<Ram>,
+    manual_timers: bool,
 }
 
-impl std::fmt::Display for DelayTimer {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.v.load(Ordering::SeqCst))
+impl Chip8Builder {
+    pub fn new() -> Self {
+        Self::default()
     }
-}
 
-impl DelayTimer {
-    pub fn new() -> DelayTimer {
-        DelayTimer {
-            v: Arc::new(AtomicU8::new(0)),
-            th: None,
-        }
+    /// Display to present the screen on, `NullDisplay` if not set.
+    pub fn display(mut self, dsp: Box<dyn Display>) -> Self {
+        self.dsp = Some(dsp);
+        self
     }
 
-    pub fn start(&mut self) {
-        let tick = Duration::from_millis((1000 / 60) as u64);
+    /// Audio to beep with, silent if not set.
+    pub fn audio(mut self, audio: Box<dyn Audio>) -> Self {
+        self.audio = Some(audio);
+        self
+    }
 
-        let v = Arc::clone(&self.v);
-        let th = std::thread::spawn(move || loop {
-            let now = Instant::now();
+    /// Keypad to read keys from, no key is ever pressed if not set.
+    pub fn input(mut self, inp: Box<dyn Input>) -> Self {
+        self.inp = Some(inp);
+        self
+    }
 
-            // Increment counter.
-            loop {
-                let curr = v.load(Ordering::SeqCst);
-                if curr > 0 {
-                    if curr == v.compare_and_swap(curr, curr - 1, Ordering::SeqCst) {
-                        break;
-                    }
-                } else {
-                    break;
-                }
-            }
+    pub fn quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
 
-            // Adjust to 60Hz.
-            if let Some(remaining) = tick.checked_sub(now.elapsed()) {
-                sleep(remaining);
+    /// Instructions per second run by `Chip8::run`. Panics if `hz` is 0,
+    /// which would never run anything.
+    pub fn clock_hz(mut self, hz: u32) -> Self {
+        assert!(hz > 0, "clock_hz must be at least 1");
+        self.clock_hz = Some(hz);
+        self
+    }
+
+    /// Time source of the delay and sound timers, `SystemClock` if not set.
+    /// Required on wasm32, see `ManualClock`.
+    pub fn clock(mut self, clock: Rc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Count the timers down only by `Chip8::tick_timers` instead of by a
+    /// clock, so that runs are deterministic. Overrides `clock`.
+    pub fn manual_timers(mut self) -> Self {
+        self.manual_timers = true;
+        self
+    }
+
+    /// Seed of the random numbers of `Cxkk`, from entropy if not set.
+    /// Together with a `ManualClock`, runs are fully reproducible.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Address to load and start programs at, 0x200 if not set.
+    /// ETI 660 programs start at 0x600.
+    pub fn start_addr(mut self, addr: u16) -> Self {
+        self.start = Some(addr);
+        self
+    }
+
+    /// 4x5 font for `Fx29`, `Font::Standard` if not set.
+    pub fn font(mut self, font: Font) -> Self {
+        self.font = font;
+        self
+    }
+
+    /// Prepared RAM to use as is, e.g. from `Ram::from_program`, instead
+    /// of an empty one. `font` is ignored since the RAM has its own.
+    pub fn ram(mut self, ram: Ram) -> Self {
+        self.ram = Some(ram);
+        self
+    }
+
+    /// Number of instructions which can be undone by `step_back`.
+    pub fn history(mut self, depth: usize) -> Self {
+        self.history = depth;
+        self
+    }
+
+    /// Number of instructions to keep for `Cpu::trace_dump`, none if not
+    /// set.
+    pub fn trace(mut self, depth: usize) -> Self {
+        self.trace = depth;
+        self
+    }
+
+    pub fn build(self) -> Chip8 {
+        let mut cpu = Cpu::with_history(self.history);
+        cpu.set_trace_depth(self.trace);
+        cpu.quirks = self.quirks;
+        cpu.clock_hz = self.clock_hz.unwrap_or(DEFAULT_CLOCK_HZ);
+        if let Some(clock) = self.clock {
+            cpu.set_clock(clock);
+        }
+        let timer_clock = if self.manual_timers {
+            let clock = Rc::new(ManualClock::new());
+            cpu.set_clock(clock.clone());
+            Some(clock)
+        } else {
+            None
+        };
+        if let Some(seed) = self.seed {
+            cpu.seed_rng(seed);
+        }
+        let mut ram = match self.ram {
+            Some(ram) => ram,
+            None => {
+                let mut ram = if self.quirks.xochip {
+                    Ram::with_size(XOCHIP_RAM_SIZE)
+                } else {
+                    Ram::new()
+                };
+                ram.font = self.font.glyphs();
+                ram
             }
-        });
-        self.th = Some(th);
+        };
+        if let Some(addr) = self.start {
+            ram.start = addr;
+        }
+        cpu.set_start_addr(ram.start);
+        Chip8 {
+            cpu,
+            ram,
+            fb: FrameBuffer::new(),
+            dsp: self.dsp.unwrap_or_else(|| Box::new(NullDisplay)),
+            audio: self.audio,
+            inp: self.inp.unwrap_or_else(|| Box::new(NullInput)),
+            beeping: false,
+            pattern: None,
+            paused: false,
+            timer_clock,
+            timer_ticks: 0,
+        }
     }
+}
 
-    pub fn get(&self) -> u8 {
-        self.v.load(Ordering::SeqCst)
+/// Trait to sound the buzzer while the sound timer is active.
+pub trait Audio {
+    fn play(&mut self);
+    fn pause(&mut self);
+    /// Play XO-CHIP 1-bit samples `pattern`, MSB first, at `rate` samples
+    /// per second instead of the plain beep. Ignored unless overridden.
+    fn set_pattern(&mut self, _pattern: [u8; 16], _rate: u32) {}
+}
+
+/// Samples per second of the XO-CHIP audio pattern.
+pub const PATTERN_RATE: u32 = 4000;
+
+/// Width of the Chip8 screen in pixels.
+pub const WIDTH: usize = 64;
+
+/// Height of the Chip8 screen in pixels.
+pub const HEIGHT: usize = 32;
+
+/// Width of the SUPER-CHIP high resolution screen in pixels.
+pub const HIRES_WIDTH: usize = 128;
+
+/// Height of the SUPER-CHIP high resolution screen in pixels.
+pub const HIRES_HEIGHT: usize = 64;
+
+/// Screen resolution.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    /// 64x32 pixels.
+    Low,
+    /// 128x64 pixels of SUPER-CHIP.
+    High,
+}
+
+impl Resolution {
+    pub fn width(self) -> usize {
+        match self {
+            Resolution::Low => WIDTH,
+            Resolution::High => HIRES_WIDTH,
+        }
     }
 
-    pub fn set(&mut self, val: u8) {
-        self.v.store(val, Ordering::SeqCst);
+    pub fn height(self) -> usize {
+        match self {
+            Resolution::Low => HEIGHT,
+            Resolution::High => HIRES_HEIGHT,
+        }
     }
 }
 
-/// Return value to determine the next `pc`.
-pub enum Res {
-    /// Increase `pc` by 2.
-    Next,
-    /// Increase `pc` by 4.
-    Skip,
-    /// Set `pc` the value.
-    Jump(u16),
+/// Trait to show the screen on a display device. The CPU draws on the
+/// `FrameBuffer` of `Chip8`, so a display only presents it.
+pub trait Display {
+    /// Show `fb`, called after every instruction which changed it.
+    /// `FrameBuffer::dirty` tells which pixels changed since the last call.
+    fn present(&mut self, fb: &FrameBuffer) -> Result<(), Error>;
+    /// Blank the device, called before presenting a screen of another
+    /// resolution and on `Chip8::reset`.
+    fn clear(&mut self) -> Result<(), Error>;
 }
 
-use self::Res::{Jump, Next, Skip};
+/// Lets the display given to `Chip8` be looked at from outside, e.g. a
+/// `RecordingDisplay` checked by a test.
+impl<D: Display> Display for Rc<RefCell<D>> {
+    fn present(&mut self, fb: &FrameBuffer) -> Result<(), Error> {
+        self.borrow_mut().present(fb)
+    }
 
-fn addr(n1: u8, n2: u8, n3: u8) -> u16 {
-    ((n1 as u16) << 8) + ((n2 as u16) << 4) + n3 as u16
+    fn clear(&mut self) -> Result<(), Error> {
+        self.borrow_mut().clear()
+    }
 }
 
-fn fontaddr(n: u8) -> u16 {
-    n as u16 * 5
+/// Display which shows nothing.
+#[derive(Debug, Default)]
+pub struct NullDisplay;
+
+impl Display for NullDisplay {
+    fn present(&mut self, _fb: &FrameBuffer) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
-fn var(x1: u8, x2: u8) -> u8 {
-    ((x1 as u8) << 4) + x2 as u8
+/// Call made to a `RecordingDisplay`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisplayCall {
+    /// Screen presented, as given by `FrameBuffer::snapshot`.
+    Present(Vec<u8>),
+    Clear,
 }
 
-fn idx(x: u8) -> usize {
-    x as usize
+/// Display remembering every call made to it, e.g. to check when the
+/// screen was presented. Give it to `Chip8` in an `Rc<RefCell<_>>` to look
+/// at `calls` afterwards.
+#[derive(Default)]
+pub struct RecordingDisplay {
+    pub calls: Vec<DisplayCall>,
 }
 
-impl Cpu {
-    fn new() -> Self {
-        let mut dt = DelayTimer::new();
-        dt.start();
-        Cpu {
-            v: [0; 16],
-            i: 0,
-            stack: [0; 16],
-            sp: 0,
-            pc: 0x200,
-            dt,
-            key: None,
-        }
+impl RecordingDisplay {
+    pub fn new() -> Self {
+        Self::default()
     }
+}
 
-    /// Send `draw` instruction to display.
-    fn draw(&self, dsp: &mut Box<Display>, x: u8, y: u8, data: Vec<u8>) -> Result<u8, ()> {
-            dsp.draw(x, y, data)
+impl Display for RecordingDisplay {
+    fn present(&mut self, fb: &FrameBuffer) -> Result<(), Error> {
+        self.calls.push(DisplayCall::Present(fb.snapshot()));
+        Ok(())
     }
 
-    /// Send `clear` instruction to display.
-    fn clear(&self, dsp: &mut Box<Display>) -> Result<(), ()> {
-        dsp.clear();
+    fn clear(&mut self) -> Result<(), Error> {
+        self.calls.push(DisplayCall::Clear);
         Ok(())
     }
+}
 
-    pub fn run(
-        &mut self,
-        ram: &mut Ram,
-        dsp: &mut Box<Display>,
-        inp: &mut mpsc::Receiver<Key>,
-    ) {
-        loop {
-            if self.pc >= 0xFFF || (self.pc + 1) >= 0xFFF {
-                break;
-            }
-            self.tick(ram, dsp, inp);
+/// Screen of `Chip8`, which the CPU XORs sprites into and takes
+/// collisions from. Frontends only render its `pixels` when presented it.
+#[derive(Clone)]
+pub struct FrameBuffer {
+    buf: [[u8; HIRES_HEIGHT]; HIRES_WIDTH],
+    res: Resolution,
+    /// Selected XO-CHIP bit planes.
+    planes: u8,
+    /// Pixels changed since the last `mark_clean`, bit `x` of row `y`.
+    dirty: [u128; HIRES_HEIGHT],
+}
+
+impl FrameBuffer {
+    pub fn new() -> Self {
+        FrameBuffer {
+            buf: [[0; HIRES_HEIGHT]; HIRES_WIDTH],
+            res: Resolution::Low,
+            planes: 1,
+            // Nothing has been shown yet.
+            dirty: [!0; HIRES_HEIGHT],
         }
     }
 
-    /// One tick of CPU.
-    pub fn tick(
-        &mut self,
-        ram: &mut Ram,
-        io: &mut Box<Display>,
-        inp: &mut mpsc::Receiver<Key>,
-    ) {
-        let pc = self.pc as usize;
-        let o1: u8 = ram.buf[pc] >> 4;
-        let o2: u8 = ram.buf[pc] & 0xf;
-        let o3: u8 = ram.buf[pc + 1] >> 4;
-        let o4: u8 = ram.buf[pc + 1] & 0xf;
-        let res = match (o1, o2, o3, o4) {
-            (0x0, 0x0, 0xE, 0x0) => {
-                trace!("00E0 - CLS");
-                self.clear(io).unwrap();
-                Next
-            }
-            (0x0, 0x0, 0xE, 0xE) => {
-                trace!("00EE - RET");
-                let pc = self.stack[self.sp as usize - 1];
-                self.sp -= 1;
-                Jump(pc + 2)
-            }
-            (0x0, n1, n2, n3) => {
-                let nnn = addr(n1, n2, n3);
-                trace!("0nnn - SYS {}", nnn);
-                Jump(nnn)
+    /// Pixels changed since the screen was last presented as
+    /// `(x, y, pixel)`, row by row, so that frontends only redraw those.
+    /// Everything is changed at first and after `CLS`, scrolls and
+    /// resolution switches.
+    pub fn dirty(&self) -> Vec<(usize, usize, u8)> {
+        let mut changed = Vec::new();
+        for y in 0..self.res.height() {
+            let row = self.dirty[y];
+            if row == 0 {
+                continue;
             }
-            (0x1, n1, n2, n3) => {
-                let nnn = addr(n1, n2, n3);
-                trace!("1nnn - JP {}", nnn);
-                Jump(nnn)
+            for x in (0..self.res.width()).filter(|x| row & (1 << x) != 0) {
+                changed.push((x, y, self.buf[x][y]));
             }
-            (0x2, n1, n2, n3) => {
-                let nnn = addr(n1, n2, n3);
-                trace!("2nnn - CALL {}", nnn);
-                self.stack[self.sp as usize] = self.pc;
-                self.sp += 1;
-                Jump(nnn)
+        }
+        changed
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.dirty.iter().any(|&row| row != 0)
+    }
+
+    fn mark_clean(&mut self) {
+        self.dirty = [0; HIRES_HEIGHT];
+    }
+
+    fn mark_all_dirty(&mut self) {
+        self.dirty = [!0; HIRES_HEIGHT];
+    }
+
+    /// Current pixels indexed by `[x][y]`, each one a mask of the planes
+    /// it is on in, so 0 to 3 and only 0 or 1 unless XO-CHIP planes are used.
+    /// Only the area of the current `resolution` is used.
+    pub fn pixels(&self) -> &[[u8; HIRES_HEIGHT]; HIRES_WIDTH] {
+        &self.buf
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        self.res
+    }
+
+    /// Screen as a binary PBM image, see `pbm`.
+    pub fn to_pbm(&self) -> Vec<u8> {
+        pbm(self.res, &self.snapshot())
+    }
+
+    /// Pixels of the current resolution row by row, each one a mask of the
+    /// planes it is on.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let (width, height) = (self.res.width(), self.res.height());
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                pixels.push(self.buf[x][y]);
             }
-            (0x3, x, k1, k2) => {
-                let kk = var(k1, k2);
-                let vx = self.v[idx(x)];
-                trace!("SE V{}({}) K({})", x, vx, kk);
-                if vx == kk {
-                    Skip
-                } else {
-                    Next
+        }
+        pixels
+    }
+
+    /// XOR sprite rows `data` at `x`, `y` in the selected planes, clipped
+    /// at the screen edges. Returns 1 if a pixel was turned off and 0
+    /// otherwise.
+    fn draw(&mut self, x: u8, y: u8, data: &[u8]) -> u8 {
+        let x = x as usize;
+        let y = y as usize;
+        let (width, height) = (self.res.width(), self.res.height());
+        let mut vf = 0;
+        for (iy, b) in data.iter().enumerate() {
+            for ix in 0..8 {
+                if x + ix >= width || y + iy >= height {
+                    continue;
                 }
-            }
-            (0x4, x, k1, k2) => {
-                let kk = var(k1, k2);
-                trace!("SNE Vx({}) K({})", x, kk);
-                if self.v[idx(x)] != kk {
-                    Skip
-                } else {
-                    Next
+                if (b >> (7 - ix)) & 0x1 == 0 {
+                    continue;
                 }
-            }
-            (0x5, x, y, 0x0) => {
-                trace!("SE Vx({}), Vy({})", x, y);
-                if self.v[idx(x)] == self.v[idx(y)] {
-                    Skip
-                } else {
-                    Next
+                let cb = &mut self.buf[x + ix][y + iy];
+                // Collision when a pixel being on is turned off.
+                if *cb & self.planes != 0 {
+                    vf = 1;
                 }
+                *cb ^= self.planes;
+                self.dirty[y + iy] |= 1 << (x + ix);
             }
-            (0x6, x, k1, k2) => {
-                let kk = var(k1, k2);
-                trace!("6xkk - LD V{}={}", x, kk);
-                self.v[idx(x)] = kk;
-                Next
-            }
-            (0x7, x, k1, k2) => {
-                let x = idx(x);
-                let kk = var(k1, k2);
-                trace!("7xkk - ADD V{} {}", x, kk);
-                self.v[x] = self.v[x].overflowing_add(kk).0;
+        }
+
+        vf
+    }
+
+    /// Turn the pixels of the selected planes off.
+    fn clear(&mut self) {
+        for col in self.buf.iter_mut() {
+            for cb in col.iter_mut() {
+                *cb &= !self.planes;
+            }
+        }
+        self.mark_all_dirty();
+    }
+
+    /// Switch between 64x32 and 128x64, clearing the screen.
+    fn set_resolution(&mut self, res: Resolution) {
+        self.res = res;
+        self.buf = [[0; HIRES_HEIGHT]; HIRES_WIDTH];
+        self.mark_all_dirty();
+    }
+
+    /// Scroll the selected planes down by `n` pixels.
+    fn scroll_down(&mut self, n: u8) {
+        let n = n as usize;
+        for x in 0..self.res.width() {
+            for y in (0..self.res.height()).rev() {
+                let from = if y >= n { self.buf[x][y - n] } else { 0 };
+                self.buf[x][y] = blend(self.buf[x][y], from, self.planes);
+            }
+        }
+        self.mark_all_dirty();
+    }
+
+    /// Scroll the selected planes right by `n` pixels.
+    fn scroll_right(&mut self, n: u8) {
+        let n = n as usize;
+        for x in (0..self.res.width()).rev() {
+            for y in 0..self.res.height() {
+                let from = if x >= n { self.buf[x - n][y] } else { 0 };
+                self.buf[x][y] = blend(self.buf[x][y], from, self.planes);
+            }
+        }
+        self.mark_all_dirty();
+    }
+
+    /// Scroll the selected planes left by `n` pixels.
+    fn scroll_left(&mut self, n: u8) {
+        let n = n as usize;
+        let width = self.res.width();
+        for x in 0..width {
+            for y in 0..self.res.height() {
+                let from = if x + n < width { self.buf[x + n][y] } else { 0 };
+                self.buf[x][y] = blend(self.buf[x][y], from, self.planes);
+            }
+        }
+        self.mark_all_dirty();
+    }
+
+    /// Select XO-CHIP bit planes which drawing, clearing and scrolls
+    /// affect, bit 0 is the first plane and bit 1 the second.
+    fn set_planes(&mut self, planes: u8) {
+        self.planes = planes;
+    }
+}
+
+impl Default for FrameBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Binary (P4) PBM image of `pixels` at `res` as given by
+/// `FrameBuffer::snapshot`, pixels on in any plane being black.
+pub fn pbm(res: Resolution, pixels: &[u8]) -> Vec<u8> {
+    let mut out = format!("P4\n{} {}\n", res.width(), res.height()).into_bytes();
+    // Widths are multiples of 8, so rows need no padding.
+    for bits in pixels.chunks(8) {
+        out.push(bits.iter().fold(0, |b, &p| b << 1 | (p != 0) as u8));
+    }
+    out
+}
+
+/// Pixel `from` moved onto `to` in the selected `planes` only.
+fn blend(to: u8, from: u8, planes: u8) -> u8 {
+    (to & !planes) | (from & planes)
+}
+
+/// Optional behaviors which differ between Chip8 interpreters.
+/// `Default` is this emulator's own mix, use a preset to follow one.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Quirks {
+    /// Enable SUPER-CHIP instructions.
+    pub schip: bool,
+    /// Sprite drawn by SUPER-CHIP `Dxy0` in low resolution.
+    pub lores_dxy0: LoresDxy0,
+    /// Enable XO-CHIP instructions.
+    pub xochip: bool,
+    /// `8xy6`/`8xyE` shift `Vy` into `Vx` instead of shifting `Vx` in place.
+    pub shift_uses_vy: bool,
+    /// `Fx55`/`Fx65` leave `I` pointing after the last register.
+    pub increment_i_on_store: bool,
+    /// `8xy1`/`8xy2`/`8xy3` reset `VF` to 0 as the COSMAC VIP did. Off by
+    /// default since SUPER-CHIP and later games leave `VF` untouched, but
+    /// on in `Quirks::chip8()`.
+    pub reset_vf: bool,
+    /// `Bxnn` jumps to `xnn + Vx` instead of `nnn + V0`.
+    pub jump_uses_vx: bool,
+    /// Sprites crossing the screen edge wrap to the other side instead of
+    /// being clipped.
+    pub wrap_sprites: bool,
+    /// `Fx1E` sets `VF` to 1 when `I` goes past 0xFFF and to 0 otherwise,
+    /// like the Amiga interpreter.
+    pub i_overflow_sets_vf: bool,
+    /// `Dxyn` waits for the next 60Hz frame if one was already drawn in
+    /// the current frame.
+    pub display_wait: bool,
+    /// Writes below 0x200, where the interpreter and the fonts live, halt
+    /// with `Halt::WriteProtected` instead of overwriting them. Not a quirk
+    /// of any interpreter but a help to find such bugs in programs.
+    pub write_protect: bool,
+}
+
+impl Quirks {
+    /// Original COSMAC VIP interpreter.
+    pub fn chip8() -> Self {
+        Quirks {
+            shift_uses_vy: true,
+            increment_i_on_store: true,
+            reset_vf: true,
+            display_wait: true,
+            ..Default::default()
+        }
+    }
+
+    /// SUPER-CHIP 1.1 on the HP48.
+    pub fn schip() -> Self {
+        Quirks {
+            schip: true,
+            lores_dxy0: LoresDxy0::Large,
+            jump_uses_vx: true,
+            ..Default::default()
+        }
+    }
+
+    /// CHIP-48 on the HP48, which most games of the early 90s were written
+    /// for. It shifts `Vx` in place and has the `Bxnn` jump of SUPER-CHIP,
+    /// but none of its instructions. `Fx55`/`Fx65` advance `I` by `x` there,
+    /// one less than `increment_i_on_store` does.
+    pub fn chip48() -> Self {
+        Quirks {
+            increment_i_on_store: true,
+            jump_uses_vx: true,
+            ..Default::default()
+        }
+    }
+
+    /// XO-CHIP as implemented by Octo.
+    pub fn xochip() -> Self {
+        Quirks {
+            schip: true,
+            lores_dxy0: LoresDxy0::Large,
+            xochip: true,
+            shift_uses_vy: true,
+            increment_i_on_store: true,
+            wrap_sprites: true,
+            ..Default::default()
+        }
+    }
+}
+
+/// Sprite drawn by SUPER-CHIP `Dxy0` in low resolution. High resolution
+/// always draws 16x16.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LoresDxy0 {
+    /// No rows at all, same as Chip8.
+    #[default]
+    Empty,
+    /// 8x16 sprite from 16 bytes.
+    Tall,
+    /// 16x16 sprite from 32 bytes.
+    Large,
+}
+
+/// Keypad the CPU reads keys from.
+pub trait Input {
+    /// Next key pressed since the last call, if any.
+    fn poll(&mut self) -> Option<Key>;
+    /// Whether key `key` (0x0 to 0xF) is held down right now.
+    fn is_pressed(&self, key: u8) -> bool;
+}
+
+/// Channel of key presses sent e.g. from another thread. A press is kept
+/// until an instruction consumes it since the channel can't tell releases.
+impl Input for mpsc::Receiver<Key> {
+    fn poll(&mut self) -> Option<Key> {
+        self.try_recv().ok()
+    }
+
+    fn is_pressed(&self, _key: u8) -> bool {
+        false
+    }
+}
+
+/// Keypad of which no key is ever pressed.
+#[derive(Debug, Default)]
+pub struct NullInput;
+
+impl Input for NullInput {
+    fn poll(&mut self) -> Option<Key> {
+        None
+    }
+
+    fn is_pressed(&self, _key: u8) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Key(pub u8);
+
+impl Key {
+    /// Key at `c` on the QWERTY layout of `1234`/`qwer`/`asdf`/`zxcv`,
+    /// `None` if `c` isn't one of them.
+    pub fn try_from_char(c: char) -> Option<Key> {
+        let key = match c {
+            '1' => Key(0x1),
+            '2' => Key(0x2),
+            '3' => Key(0x3),
+            'q' => Key(0x4),
+            'w' => Key(0x5),
+            'e' => Key(0x6),
+            'a' => Key(0x7),
+            's' => Key(0x8),
+            'd' => Key(0x9),
+            'z' => Key(0xA),
+            'x' => Key(0x0),
+            'c' => Key(0xB),
+            '4' => Key(0xC),
+            'r' => Key(0xD),
+            'f' => Key(0xE),
+            'v' => Key(0xF),
+            _ => return None,
+        };
+        Some(key)
+    }
+
+    /// Key `n`, `None` unless `n` is 0 to F.
+    pub fn from_nibble(n: u8) -> Option<Key> {
+        if n < 0x10 {
+            Some(Key(n))
+        } else {
+            None
+        }
+    }
+}
+
+/// `Key(0x99)`, which isn't a key, for unknown characters. Prefer
+/// `Key::try_from_char`.
+impl std::convert::From<char> for Key {
+    fn from(c: char) -> Key {
+        Key::try_from_char(c).unwrap_or(Key(0x99))
+    }
+}
+
+#[derive(Debug)]
+pub struct Cpu {
+    /// 8bit general purpose Registers.
+    v: [u8; 16],
+    /// Index register.
+    i: u16,
+    /// Stack,
+    stack: [u16; 16],
+    /// Address called by the `CALL` of each stack entry.
+    callees: [u16; 16],
+    /// Stack pointer.
+    sp: u16,
+    /// Program counter.
+    pub pc: u16,
+    /// Address `pc` starts from.
+    start: u16,
+    /// Delay timer.
+    pub dt: DelayTimer,
+    /// Sound timer.
+    pub st: DelayTimer,
+    /// Key being entered.
+    key: Option<Key>,
+    /// Key pressed during `Fx0A`, waiting to be released.
+    wait_key: Option<u8>,
+    /// SUPER-CHIP RPL user flags.
+    rpl: [u8; 8],
+    /// XO-CHIP audio pattern loaded by `F002`.
+    pattern: Option<[u8; 16]>,
+    /// Number of executed instructions.
+    cycles: u64,
+    /// Instruction executed last.
+    last: Option<Instruction>,
+    /// Set once the program jumps to itself, nothing is executed after.
+    halted: bool,
+    /// Current screen resolution.
+    res: Resolution,
+    /// XO-CHIP bit planes selected by `Fn01`.
+    planes: u8,
+    /// Random number generator of `Cxkk`.
+    rng: StdRng,
+    /// Time source shared with the timers.
+    clock: Rc<dyn Clock>,
+    /// 60Hz frame a sprite was last drawn in, for `Quirks::display_wait`.
+    drawn_frame: Option<u64>,
+    /// Interpreter variations to follow.
+    pub quirks: Quirks,
+    /// Instructions executed per second by `Chip8::run`.
+    pub clock_hz: u32,
+    /// Receives `CpuEvent`s if set.
+    events: Option<EventSink>,
+    /// Whether the sound timer was active as last reported to `events`.
+    sound: bool,
+    /// Called before each instruction if set.
+    pre_hook: Option<Hook<PreHook>>,
+    /// Called after each instruction if set.
+    post_hook: Option<Hook<PostHook>>,
+    /// Undo records of the last executed instructions.
+    history: VecDeque<Record>,
+    /// Maximum number of records in `history`, 0 disables it.
+    history_depth: usize,
+    /// RAM bytes overwritten by the current instruction, for its record
+    /// in `history`.
+    written: Vec<Change>,
+    /// `(pc, opcode)` of the last executed instructions, oldest first.
+    trace: VecDeque<(u16, u16)>,
+    /// Maximum number of entries in `trace`, 0 disables it.
+    trace_depth: usize,
+    /// Executed instructions by `Instruction::pattern`, if profiling.
+    profile: Option<HashMap<&'static str, u64>>,
+}
+
+/// Something the CPU did, for frontends showing it live.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CpuEvent {
+    /// Instruction `opcode` at `pc` was executed.
+    InstructionExecuted { pc: u16, opcode: u16 },
+    /// Register `Vreg` changed to `value`.
+    RegisterWritten { reg: u8, value: u8 },
+    /// Sprite was drawn at `x`, `y`.
+    Draw { x: u8, y: u8 },
+    /// Sound timer started or stopped.
+    SoundStateChanged { on: bool },
+}
+
+/// Callback given to `Cpu::set_event_sink`.
+struct EventSink(Box<dyn FnMut(CpuEvent)>);
+
+impl std::fmt::Debug for EventSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "EventSink")
+    }
+}
+
+/// Callback of `Cpu::set_pre_hook`, given the registers before `Instruction`
+/// is executed.
+pub type PreHook = dyn FnMut(&CpuState, &Instruction);
+
+/// Callback of `Cpu::set_post_hook`, given the registers after `Instruction`
+/// was executed and how it moved the program counter.
+pub type PostHook = dyn FnMut(&CpuState, &Instruction, Res);
+
+/// Callback installed on `Cpu`.
+struct Hook<F: ?Sized>(Box<F>);
+
+impl<F: ?Sized> std::fmt::Debug for Hook<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Hook")
+    }
+}
+
+/// Copy of the CPU registers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuState {
+    pub v: [u8; 16],
+    pub i: u16,
+    pub stack: [u16; 16],
+    /// Address called by the `CALL` of each stack entry.
+    pub callees: [u16; 16],
+    pub sp: u16,
+    pub pc: u16,
+    pub dt: u8,
+    pub st: u8,
+    pub key: Option<Key>,
+    pub rpl: [u8; 8],
+    pub pattern: Option<[u8; 16]>,
+    pub cycles: u64,
+    /// Instruction executed last, `None` before the first one.
+    pub last: Option<Instruction>,
+}
+
+impl CpuState {
+    /// Return addresses in use, the most recent last.
+    pub fn call_stack(&self) -> &[u16] {
+        &self.stack[..self.sp as usize]
+    }
+}
+
+/// Subroutine call in progress, see `Cpu::backtrace`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+    /// Address of the `CALL`.
+    pub caller: u16,
+    /// Address of the called subroutine.
+    pub callee: u16,
+}
+
+impl std::fmt::Display for Frame {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:#05X} called from {:#05X}", self.callee, self.caller)
+    }
+}
+
+/// What is needed to undo one instruction.
+#[derive(Debug)]
+struct Record {
+    /// Address of the instruction.
+    pc: u16,
+    /// Instruction executed before it.
+    last: Option<Instruction>,
+    /// What the instruction changed with the previous values, in order.
+    changes: Vec<Change>,
+}
+
+/// Register or RAM byte changed by an instruction, with the value it had
+/// before.
+#[derive(Debug, Clone, Copy)]
+enum Change {
+    V(u8, u8),
+    I(u16),
+    Sp(u16),
+    /// Stack entry with its return address and callee.
+    Stack(u8, u16, u16),
+    Dt(u8),
+    St(u8),
+    Key(Option<Key>),
+    Rpl(u8, u8),
+    Pattern(Option<[u8; 16]>),
+    Ram(u16, u8),
+}
+
+/// Address programs are loaded at and started from unless told otherwise.
+pub const DEFAULT_START_ADDR: u16 = 0x200;
+
+/// Number of instructions worth keeping in `Cpu::trace_dump`.
+pub const DEFAULT_TRACE_DEPTH: usize = 256;
+
+/// Number of traced instructions `Halt::UnknownOpcode` carries.
+const HALT_TRACE_LEN: usize = 8;
+
+/// Default CPU speed in instructions per second.
+pub const DEFAULT_CLOCK_HZ: u32 = 500;
+
+/// Source of monotonic time for the timers, replaceable e.g. in tests.
+pub trait Clock: std::fmt::Debug {
+    /// Time passed since an arbitrary fixed point.
+    fn now(&self) -> Duration;
+}
+
+/// `Clock` backed by `Instant`.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug)]
+pub struct SystemClock(Instant);
+
+#[cfg(not(target_arch = "wasm32"))]
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock(Instant::now())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.0.elapsed()
+    }
+}
+
+/// `Clock` moved forward by hand, for hosts without `Instant` such as
+/// `wasm32-unknown-unknown` where the page drives the emulator.
+#[derive(Debug, Default)]
+pub struct ManualClock(Cell<Duration>);
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Move the clock forward by `d`.
+    pub fn advance(&self, d: Duration) {
+        self.0.set(self.0.get() + d);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        self.0.get()
+    }
+}
+
+/// Clock used when none is given, `Instant` isn't available on wasm32
+/// so the timers there only move with a clock set by `Chip8Builder::clock`.
+#[cfg(not(target_arch = "wasm32"))]
+fn default_clock() -> Rc<dyn Clock> {
+    Rc::new(SystemClock::new())
+}
+
+#[cfg(target_arch = "wasm32")]
+fn default_clock() -> Rc<dyn Clock> {
+    Rc::new(ManualClock::new())
+}
+
+/// Number of 60Hz ticks in `d`.
+fn ticks(d: Duration) -> u64 {
+    (d.as_nanos() * 60 / 1_000_000_000) as u64
+}
+
+/// 60Hz countdown timer, used for both delay and sound timers.
+/// The value is computed from the time passed since it was last set.
+#[derive(Debug)]
+pub struct DelayTimer {
+    clock: Rc<dyn Clock>,
+    /// When the timer was last set.
+    set_at: Duration,
+    /// Value the timer was last set to.
+    val: u8,
+    /// When the timer was paused, if it is.
+    paused_at: Option<Duration>,
+}
+
+impl std::fmt::Display for DelayTimer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.get())
+    }
+}
+
+impl DelayTimer {
+    pub fn new() -> DelayTimer {
+        DelayTimer::with_clock(default_clock())
+    }
+
+    /// Create timer counting down by `clock`.
+    pub fn with_clock(clock: Rc<dyn Clock>) -> DelayTimer {
+        let set_at = clock.now();
+        DelayTimer {
+            clock,
+            set_at,
+            val: 0,
+            paused_at: None,
+        }
+    }
+
+    /// Value counted down at every 60Hz tick of the clock since it was set,
+    /// so that all timers and `Cpu::time_until_next_tick` are in phase.
+    pub fn get(&self) -> u8 {
+        let ticks = ticks(self.now()).saturating_sub(ticks(self.set_at));
+        self.val.saturating_sub(ticks.min(0xFF) as u8)
+    }
+
+    pub fn set(&mut self, val: u8) {
+        self.set_at = self.now();
+        self.val = val;
+    }
+
+    /// Stop counting down until `resume`.
+    pub fn pause(&mut self) {
+        if self.paused_at.is_none() {
+            self.paused_at = Some(self.clock.now());
+        }
+    }
+
+    /// Continue counting down from where `pause` stopped.
+    pub fn resume(&mut self) {
+        if let Some(paused_at) = self.paused_at.take() {
+            self.set_at += self.clock.now().saturating_sub(paused_at);
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+
+    /// Time the timer counts at, which stands still while paused.
+    fn now(&self) -> Duration {
+        self.paused_at.unwrap_or_else(|| self.clock.now())
+    }
+}
+
+impl Default for DelayTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reason why the CPU stopped executing instructions.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Halt {
+    /// Program counter points to where a whole instruction can't be fetched.
+    PcOutOfRange { pc: u16, backtrace: Vec<Frame> },
+    /// Instruction at `pc` doesn't decode to any known opcode. `trace`
+    /// holds the last few `(pc, opcode)` executed before it, oldest first
+    /// and empty unless tracing is on, see `Cpu::set_trace_depth`.
+    UnknownOpcode {
+        pc: u16,
+        opcode: u16,
+        trace: Vec<(u16, u16)>,
+        backtrace: Vec<Frame>,
+    },
+    /// Program finished with SUPER-CHIP `00FD`.
+    Exit,
+    /// Program ended by jumping to the jump itself at the address.
+    SpinLoop(u16),
+    /// `CALL` at `pc` with all 16 stack entries in use.
+    StackOverflow { pc: u16, backtrace: Vec<Frame> },
+    /// `RET` at `pc` with an empty stack.
+    StackUnderflow { pc: u16, backtrace: Vec<Frame> },
+    /// Instruction at `pc` wrote to `addr` protected by
+    /// `Quirks::write_protect`.
+    WriteProtected {
+        pc: u16,
+        addr: u16,
+        backtrace: Vec<Frame>,
+    },
+    /// Instruction at `pc` accessed `addr` outside of RAM.
+    AddressOutOfRange {
+        pc: u16,
+        addr: usize,
+        backtrace: Vec<Frame>,
+    },
+    /// `Display` failed to present the screen changed by the instruction at
+    /// `pc`.
+    DisplayFailed {
+        pc: u16,
+        error: Error,
+        backtrace: Vec<Frame>,
+    },
+}
+
+impl Halt {
+    /// Subroutine calls the program was in when it halted, the innermost
+    /// first. Empty for halts which aren't errors.
+    pub fn backtrace(&self) -> &[Frame] {
+        match self {
+            Halt::PcOutOfRange { backtrace, .. }
+            | Halt::UnknownOpcode { backtrace, .. }
+            | Halt::StackOverflow { backtrace, .. }
+            | Halt::StackUnderflow { backtrace, .. }
+            | Halt::WriteProtected { backtrace, .. }
+            | Halt::AddressOutOfRange { backtrace, .. }
+            | Halt::DisplayFailed { backtrace, .. } => backtrace,
+            _ => &[],
+        }
+    }
+}
+
+impl std::fmt::Display for Halt {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Halt::PcOutOfRange { pc, .. } => {
+                write!(f, "program counter left addressable memory at {:#05X}", pc)
+            }
+            Halt::UnknownOpcode {
+                pc, opcode, trace, ..
+            } => {
+                write!(f, "unknown opcode {:04X} at {:#05X}", opcode, pc)?;
+                if !trace.is_empty() {
+                    let trace: Vec<_> = trace
+                        .iter()
+                        .map(|(pc, opcode)| format!("{:#05X}: {:04X}", pc, opcode))
+                        .collect();
+                    write!(f, " after [{}]", trace.join(", "))?;
+                }
+                Ok(())
+            }
+            Halt::Exit => write!(f, "program exited via 00FD"),
+            Halt::SpinLoop(pc) => {
+                write!(f, "program halted by jumping to itself at {:#05X}", pc)
+            }
+            Halt::StackOverflow { pc, .. } => write!(f, "stack overflow at {:#05X}", pc),
+            Halt::StackUnderflow { pc, .. } => write!(f, "stack underflow at {:#05X}", pc),
+            Halt::WriteProtected { pc, addr, .. } => {
+                write!(f, "write to protected address {:#05X} at {:#05X}", addr, pc)
+            }
+            Halt::AddressOutOfRange { pc, addr, .. } => {
+                write!(f, "access to {:#05X} out of RAM at {:#05X}", addr, pc)
+            }
+            Halt::DisplayFailed { pc, error, .. } => write!(f, "{} at {:#05X}", error, pc),
+        }?;
+        for frame in self.backtrace() {
+            write!(f, ", called from {:#05X}", frame.caller)?;
+        }
+        Ok(())
+    }
+}
+
+/// Return value to determine the next `pc`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Res {
+    /// Increase `pc` by 2.
+    Next,
+    /// Skip over the next instruction, increasing `pc` by 4, or by 6 when
+    /// it is the XO-CHIP `F000 nnnn`.
+    Skip,
+    /// Set `pc` the value.
+    Jump(u16),
+}
+
+use self::Res::{Jump, Next, Skip};
+
+/// What one `Cpu::step` executed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Step {
+    /// Address the instruction was at.
+    pub pc: u16,
+    /// First two bytes of the instruction.
+    pub opcode: u16,
+    pub ins: Instruction,
+    /// How `pc` moved on.
+    pub res: Res,
+}
+
+/// End of the interpreter area guarded by `Quirks::write_protect`.
+const PROTECTED_END: usize = 0x200;
+
+/// Address of the SUPER-CHIP 8x10 font, right after the 4x5 font.
+const BIGFONT_ADDR: u16 = 0x50;
+
+/// Built-in 4x5 fonts of hex digits pointed at by `Fx29`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Font {
+    /// Font used by most modern interpreters.
+    #[default]
+    Standard,
+    /// Font of the original COSMAC VIP interpreter.
+    Vip,
+    /// Font of the DREAM 6800 CHIPOS.
+    Dream6800,
+    /// Font of the ETI 660.
+    Eti660,
+}
+
+impl Font {
+    /// 5 rows of each glyph from 0 to F.
+    #[rustfmt::skip]
+    pub fn glyphs(self) -> [[u8; 5]; 16] {
+        match self {
+            Font::Standard => [
+                [0xF0, 0x90, 0x90, 0x90, 0xF0], [0x20, 0x60, 0x20, 0x20, 0x70],
+                [0xF0, 0x10, 0xF0, 0x80, 0xF0], [0xF0, 0x10, 0xF0, 0x10, 0xF0],
+                [0x90, 0x90, 0xF0, 0x10, 0x10], [0xF0, 0x80, 0xF0, 0x10, 0xF0],
+                [0xF0, 0x80, 0xF0, 0x90, 0xF0], [0xF0, 0x10, 0x20, 0x40, 0x40],
+                [0xF0, 0x90, 0xF0, 0x90, 0xF0], [0xF0, 0x90, 0xF0, 0x10, 0xF0],
+                [0xF0, 0x90, 0xF0, 0x90, 0x90], [0xE0, 0x90, 0xE0, 0x90, 0xE0],
+                [0xF0, 0x80, 0x80, 0x80, 0xF0], [0xE0, 0x90, 0x90, 0x90, 0xE0],
+                [0xF0, 0x80, 0xF0, 0x80, 0xF0], [0xF0, 0x80, 0xF0, 0x80, 0x80],
+            ],
+            Font::Vip => [
+                [0xF0, 0x90, 0x90, 0x90, 0xF0], [0x60, 0x20, 0x20, 0x20, 0x70],
+                [0xF0, 0x10, 0xF0, 0x80, 0xF0], [0xF0, 0x10, 0xF0, 0x10, 0xF0],
+                [0xA0, 0xA0, 0xF0, 0x20, 0x20], [0xF0, 0x80, 0xF0, 0x10, 0xF0],
+                [0xF0, 0x80, 0xF0, 0x90, 0xF0], [0xF0, 0x10, 0x10, 0x10, 0x10],
+                [0xF0, 0x90, 0xF0, 0x90, 0xF0], [0xF0, 0x90, 0xF0, 0x10, 0xF0],
+                [0xF0, 0x90, 0xF0, 0x90, 0x90], [0xF0, 0x50, 0x70, 0x50, 0xF0],
+                [0xF0, 0x80, 0x80, 0x80, 0xF0], [0xF0, 0x50, 0x50, 0x50, 0xF0],
+                [0xF0, 0x80, 0xF0, 0x80, 0xF0], [0xF0, 0x80, 0xF0, 0x80, 0x80],
+            ],
+            Font::Dream6800 => [
+                [0xE0, 0xA0, 0xA0, 0xA0, 0xE0], [0x40, 0x40, 0x40, 0x40, 0x40],
+                [0xE0, 0x20, 0xE0, 0x80, 0xE0], [0xE0, 0x20, 0xE0, 0x20, 0xE0],
+                [0x80, 0xA0, 0xA0, 0xE0, 0x20], [0xE0, 0x80, 0xE0, 0x20, 0xE0],
+                [0xE0, 0x80, 0xE0, 0xA0, 0xE0], [0xE0, 0x20, 0x20, 0x20, 0x20],
+                [0xE0, 0xA0, 0xE0, 0xA0, 0xE0], [0xE0, 0xA0, 0xE0, 0x20, 0xE0],
+                [0xE0, 0xA0, 0xE0, 0xA0, 0xA0], [0xC0, 0xA0, 0xE0, 0xA0, 0xC0],
+                [0xE0, 0x80, 0x80, 0x80, 0xE0], [0xC0, 0xA0, 0xA0, 0xA0, 0xC0],
+                [0xE0, 0x80, 0xE0, 0x80, 0xE0], [0xE0, 0x80, 0xC0, 0x80, 0x80],
+            ],
+            Font::Eti660 => [
+                [0xE0, 0xA0, 0xA0, 0xA0, 0xE0], [0x20, 0x20, 0x20, 0x20, 0x20],
+                [0xE0, 0x20, 0xE0, 0x80, 0xE0], [0xE0, 0x20, 0xE0, 0x20, 0xE0],
+                [0xA0, 0xA0, 0xE0, 0x20, 0x20], [0xE0, 0x80, 0xE0, 0x20, 0xE0],
+                [0xE0, 0x80, 0xE0, 0xA0, 0xE0], [0xE0, 0x20, 0x20, 0x20, 0x20],
+                [0xE0, 0xA0, 0xE0, 0xA0, 0xE0], [0xE0, 0xA0, 0xE0, 0x20, 0xE0],
+                [0xE0, 0xA0, 0xE0, 0xA0, 0xA0], [0x80, 0x80, 0xE0, 0xA0, 0xE0],
+                [0xE0, 0x80, 0x80, 0x80, 0xE0], [0x20, 0x20, 0xE0, 0xA0, 0xE0],
+                [0xE0, 0x80, 0xE0, 0x80, 0xE0], [0xE0, 0x80, 0xC0, 0x80, 0x80],
+            ],
+        }
+    }
+}
+
+fn fontaddr(base: u16, n: u8) -> u16 {
+    base + n as u16 * 5
+}
+
+fn bigfontaddr(n: u8) -> u16 {
+    BIGFONT_ADDR + (n & 0xF) as u16 * 10
+}
+
+fn idx(x: u8) -> usize {
+    x as usize
+}
+
+impl Cpu {
+    /// Create CPU which keeps undo records of the last `depth` instructions.
+    pub fn with_history(depth: usize) -> Self {
+        let clock = default_clock();
+        let dt = DelayTimer::with_clock(Rc::clone(&clock));
+        let st = DelayTimer::with_clock(Rc::clone(&clock));
+        Cpu {
+            v: [0; 16],
+            i: 0,
+            stack: [0; 16],
+            callees: [0; 16],
+            sp: 0,
+            pc: DEFAULT_START_ADDR,
+            start: DEFAULT_START_ADDR,
+            dt,
+            st,
+            key: None,
+            wait_key: None,
+            rpl: [0; 8],
+            pattern: None,
+            cycles: 0,
+            last: None,
+            halted: false,
+            res: Resolution::Low,
+            planes: 1,
+            rng: StdRng::from_entropy(),
+            clock,
+            drawn_frame: None,
+            quirks: Quirks::default(),
+            clock_hz: DEFAULT_CLOCK_HZ,
+            events: None,
+            sound: false,
+            pre_hook: None,
+            post_hook: None,
+            history: VecDeque::with_capacity(depth),
+            history_depth: depth,
+            written: Vec::new(),
+            trace: VecDeque::new(),
+            trace_depth: 0,
+            profile: None,
+        }
+    }
+
+    /// Reset registers, stack and counters to the power-on state.
+    /// RPL user flags are kept, they survive resets like on the HP48.
+    pub fn reset(&mut self) {
+        self.v = [0; 16];
+        self.i = 0;
+        self.stack = [0; 16];
+        self.callees = [0; 16];
+        self.sp = 0;
+        self.pc = self.start;
+        self.dt.set(0);
+        self.st.set(0);
+        self.key = None;
+        self.wait_key = None;
+        self.pattern = None;
+        self.cycles = 0;
+        self.last = None;
+        self.halted = false;
+        self.res = Resolution::Low;
+        self.planes = 1;
+        self.drawn_frame = None;
+        self.history.clear();
+        self.trace.clear();
+    }
+
+    /// Remember `(pc, opcode)` of the last `depth` executed instructions
+    /// for `trace_dump`, 0 to stop. `DEFAULT_TRACE_DEPTH` is a good start.
+    pub fn set_trace_depth(&mut self, depth: usize) {
+        self.trace_depth = depth;
+        while self.trace.len() > depth {
+            self.trace.pop_front();
+        }
+    }
+
+    /// `(pc, opcode)` of the last executed instructions, oldest first.
+    pub fn trace_dump(&self) -> Vec<(u16, u16)> {
+        self.trace.iter().copied().collect()
+    }
+
+    /// Start or stop counting executed instructions for
+    /// `opcode_histogram`. Starting again resets the counts.
+    pub fn set_profiling(&mut self, on: bool) {
+        self.profile = if on { Some(HashMap::new()) } else { None };
+    }
+
+    /// How many times each kind of instruction was executed while
+    /// profiling, by `Instruction::pattern` and the most frequent first.
+    pub fn opcode_histogram(&self) -> Vec<(&'static str, u64)> {
+        let mut counts: Vec<_> = match &self.profile {
+            Some(profile) => profile.iter().map(|(p, n)| (*p, *n)).collect(),
+            None => Vec::new(),
+        };
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        counts
+    }
+
+    /// Make the timers count down by `clock`, keeping their current values.
+    pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+        let (dt, st) = (self.dt.get(), self.st.get());
+        self.dt = DelayTimer::with_clock(Rc::clone(&clock));
+        self.dt.set(dt);
+        self.st = DelayTimer::with_clock(Rc::clone(&clock));
+        self.st.set(st);
+        self.clock = clock;
+    }
+
+    /// Number of 60Hz frames passed by `clock`.
+    fn frame(&self) -> u64 {
+        ticks(self.clock.now())
+    }
+
+    /// Time until the timers next count down, to present frames in phase
+    /// with them.
+    pub fn time_until_next_tick(&self) -> Duration {
+        let next = Duration::from_nanos(((self.frame() + 1) * 1_000_000_000).div_ceil(60));
+        next.saturating_sub(self.clock.now())
+    }
+
+    /// Seed the generator of `Cxkk` so that programs using it are reproducible.
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Start running from `addr` instead of 0x200, now and after `reset`.
+    pub fn set_start_addr(&mut self, addr: u16) {
+        self.start = addr;
+        self.pc = addr;
+    }
+
+    /// Number of instructions executed so far.
+    pub fn cycle_count(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Start counting executed instructions from 0 again, e.g. to measure
+    /// a section of a program.
+    pub fn reset_cycle_count(&mut self) {
+        self.cycles = 0;
+    }
+
+    /// Subroutine calls in progress, the innermost first. Useful to tell
+    /// how a program got to where it halted.
+    pub fn backtrace(&self) -> Vec<Frame> {
+        (0..self.sp as usize)
+            .rev()
+            .map(|n| Frame {
+                caller: self.stack[n].wrapping_sub(2),
+                callee: self.callees[n],
+            })
+            .collect()
+    }
+
+    /// Current screen resolution.
+    pub fn resolution(&self) -> Resolution {
+        self.res
+    }
+
+    /// Whether the program ended by jumping to itself.
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// SUPER-CHIP RPL user flags.
+    pub fn rpl(&self) -> [u8; 8] {
+        self.rpl
+    }
+
+    /// Restore RPL user flags written by `save_rpl`.
+    pub fn load_rpl<S: Read>(&mut self, mut stream: S) -> Result<(), Error> {
+        stream.read_exact(&mut self.rpl)?;
+        Ok(())
+    }
+
+    /// XO-CHIP audio pattern, `None` until the program loads one.
+    pub fn pattern(&self) -> Option<[u8; 16]> {
+        self.pattern
+    }
+
+    /// Write RPL user flags so that they survive restarting the emulator.
+    pub fn save_rpl<W: Write>(&self, mut stream: W) -> Result<(), Error> {
+        stream.write_all(&self.rpl)?;
+        Ok(())
+    }
+
+    /// Copy of the registers, for debuggers and overlays.
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            v: self.v,
+            i: self.i,
+            stack: self.stack,
+            callees: self.callees,
+            sp: self.sp,
+            pc: self.pc,
+            dt: self.dt.get(),
+            st: self.st.get(),
+            key: self.key,
+            rpl: self.rpl,
+            pattern: self.pattern,
+            cycles: self.cycles,
+            last: self.last,
+        }
+    }
+
+    /// Restore registers and RAM to before the last executed instruction.
+    /// Returns `false` if there is no history left.
+    pub fn step_back(&mut self, ram: &mut Ram) -> bool {
+        let record = match self.history.pop_back() {
+            Some(record) => record,
+            None => return false,
+        };
+        for change in record.changes.into_iter().rev() {
+            match change {
+                Change::V(x, val) => self.v[idx(x)] = val,
+                Change::I(i) => self.i = i,
+                Change::Sp(sp) => self.sp = sp,
+                Change::Stack(n, ret, callee) => {
+                    self.stack[n as usize] = ret;
+                    self.callees[n as usize] = callee;
+                }
+                Change::Dt(val) => self.dt.set(val),
+                Change::St(val) => self.st.set(val),
+                Change::Key(key) => self.key = key,
+                Change::Rpl(n, val) => self.rpl[n as usize] = val,
+                Change::Pattern(pattern) => self.pattern = pattern,
+                // Recorded addresses were written before, so are in RAM.
+                Change::Ram(addr, val) => {
+                    let _ = ram.write(addr, val);
+                }
+            }
+        }
+        self.pc = record.pc;
+        self.cycles = self.cycles.saturating_sub(1);
+        self.last = record.last;
+        self.halted = false;
+        true
+    }
+
+    /// Registers which differ from `before`, with their values there.
+    fn changes_since(&self, before: &CpuState) -> Vec<Change> {
+        let mut changes = Vec::new();
+        for (x, &val) in before.v.iter().enumerate() {
+            if self.v[x] != val {
+                changes.push(Change::V(x as u8, val));
+            }
+        }
+        if self.i != before.i {
+            changes.push(Change::I(before.i));
+        }
+        if self.sp != before.sp {
+            changes.push(Change::Sp(before.sp));
+        }
+        let entries = before.stack.iter().zip(before.callees.iter());
+        for (n, (&ret, &callee)) in entries.enumerate() {
+            if self.stack[n] != ret || self.callees[n] != callee {
+                changes.push(Change::Stack(n as u8, ret, callee));
+            }
+        }
+        // Timers also change by themselves, which is undone as well.
+        if self.dt.get() != before.dt {
+            changes.push(Change::Dt(before.dt));
+        }
+        if self.st.get() != before.st {
+            changes.push(Change::St(before.st));
+        }
+        if self.key != before.key {
+            changes.push(Change::Key(before.key));
+        }
+        for (n, &val) in before.rpl.iter().enumerate() {
+            if self.rpl[n] != val {
+                changes.push(Change::Rpl(n as u8, val));
+            }
+        }
+        if self.pattern != before.pattern {
+            changes.push(Change::Pattern(before.pattern));
+        }
+        changes
+    }
+
+    /// Address `offset` bytes after `I`, wrapping around the end of RAM
+    /// which XO-CHIP's 16 bit `I` can point close to.
+    fn at_i(&self, ram: &Ram, offset: usize) -> u16 {
+        ((self.i as usize + offset) % ram.size()) as u16
+    }
+
+    /// Read a byte from RAM, halting if `addr` is out of it.
+    fn load(&self, ram: &Ram, addr: u16) -> Result<u8, Halt> {
+        ram.read(addr).map_err(|e| self.out_of_range(e))
+    }
+
+    /// Write a byte to RAM, remembering the old value for the history.
+    fn store(&mut self, ram: &mut Ram, addr: u16, val: u8) -> Result<(), Halt> {
+        if self.quirks.write_protect && (addr as usize) < PROTECTED_END {
+            return Err(Halt::WriteProtected {
+                pc: self.pc,
+                addr,
+                backtrace: self.backtrace(),
+            });
+        }
+        if self.history_depth > 0 {
+            let old = self.load(ram, addr)?;
+            self.written.push(Change::Ram(addr, old));
+        }
+        ram.write(addr, val).map_err(|e| self.out_of_range(e))
+    }
+
+    fn out_of_range(&self, e: Error) -> Halt {
+        match e {
+            Error::AddressOutOfRange(addr) => Halt::AddressOutOfRange {
+                pc: self.pc,
+                addr,
+                backtrace: self.backtrace(),
+            },
+            _ => unreachable!("RAM access fails only out of range"),
+        }
+    }
+
+    fn unknown_opcode(&self, opcode: u16) -> Halt {
+        // The last entry is the unknown opcode itself.
+        let end = self.trace.len().saturating_sub(1);
+        let start = end.saturating_sub(HALT_TRACE_LEN);
+        Halt::UnknownOpcode {
+            pc: self.pc,
+            opcode,
+            trace: self.trace.range(start..end).copied().collect(),
+            backtrace: self.backtrace(),
+        }
+    }
+
+    /// Switch `fb` to `res`, clearing it.
+    fn set_resolution(&mut self, fb: &mut FrameBuffer, res: Resolution) {
+        self.res = res;
+        fb.set_resolution(res);
+    }
+
+    /// Pixels to scroll in the current resolution. SUPER-CHIP counts scroll
+    /// amounts in high resolution pixels, so low resolution moves half.
+    fn scroll_amount(&self, n: u8) -> u8 {
+        match self.res {
+            Resolution::Low => n / 2,
+            Resolution::High => n,
+        }
+    }
+
+    /// Number of rows and whether rows are 16 pixels wide for `Dxyn`.
+    fn sprite_size(&self, n: u8) -> (usize, bool) {
+        if n != 0 || !self.quirks.schip {
+            return (idx(n), false);
+        }
+        match (self.res, self.quirks.lores_dxy0) {
+            (Resolution::High, _) | (Resolution::Low, LoresDxy0::Large) => (16, true),
+            (Resolution::Low, LoresDxy0::Tall) => (16, false),
+            (Resolution::Low, LoresDxy0::Empty) => (0, false),
+        }
+    }
+
+    /// Sprite position of `Vx`, `Vy` wrapped around the current resolution.
+    fn origin(&self, x: u8, y: u8) -> (u8, u8) {
+        let vx = self.v[idx(x)] as usize % self.res.width();
+        let vy = self.v[idx(y)] as usize % self.res.height();
+        (vx as u8, vy as u8)
+    }
+
+    /// Draw a sprite of `bytes`, 16 pixels wide if `wide`, returning VF.
+    fn draw_sprite(&self, fb: &mut FrameBuffer, x: u8, y: u8, bytes: &[u8], wide: bool) -> u8 {
+        if wide {
+            // 16 pixel wide rows are two bytes, drawn as two 8 pixel wide columns.
+            let (mut left, mut right) = ([0; 16], [0; 16]);
+            let rows = bytes.len() / 2;
+            for (n, row) in bytes.chunks_exact(2).enumerate() {
+                left[n] = row[0];
+                right[n] = row[1];
+            }
+            let vf = self.draw_column(fb, x, y, &left[..rows]);
+            vf | self.draw_column(fb, x.saturating_add(8), y, &right[..rows])
+        } else {
+            self.draw_column(fb, x, y, bytes)
+        }
+    }
+
+    /// Draw 8 pixel wide `rows`, clipped at the screen edges unless
+    /// `Quirks::wrap_sprites` is set.
+    fn draw_column(&self, fb: &mut FrameBuffer, x: u8, y: u8, rows: &[u8]) -> u8 {
+        if !self.quirks.wrap_sprites {
+            return fb.draw(x, y, rows);
+        }
+        let (width, height) = (self.res.width(), self.res.height());
+        let x = x as usize % width;
+        let mut vf = 0;
+        for (n, &row) in rows.iter().enumerate() {
+            let y = ((y as usize + n) % height) as u8;
+            vf |= fb.draw(x as u8, y, &[row]);
+            if x + 8 > width {
+                // Pixels past the right edge continue from the left one.
+                vf |= fb.draw(0, y, &[row << (width - x)]);
+            }
+        }
+        vf
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn run(&mut self, ram: &mut Ram, fb: &mut FrameBuffer, inp: &mut Box<dyn Input>) -> Halt {
+        loop {
+            if let Err(halt) = self.tick(ram, fb, inp) {
+                return halt;
+            }
+        }
+    }
+
+    /// One tick of CPU.
+    pub fn tick(
+        &mut self,
+        ram: &mut Ram,
+        fb: &mut FrameBuffer,
+        inp: &mut Box<dyn Input>,
+    ) -> Result<(), Halt> {
+        self.step(ram, fb, inp).map(|_| ())
+    }
+
+    /// Execute one instruction, returning what was executed.
+    pub fn step(
+        &mut self,
+        ram: &mut Ram,
+        fb: &mut FrameBuffer,
+        inp: &mut Box<dyn Input>,
+    ) -> Result<Step, Halt> {
+        if self.halted {
+            return Err(Halt::SpinLoop(self.pc));
+        }
+        let pc = self.pc;
+        // Both bytes of the instruction must be in RAM, so a PC landing
+        // on the last byte halts the same way as one past the end.
+        let code = match ram.read_range(self.pc, 4) {
+            Ok(code) => code,
+            Err(_) => ram.read_range(self.pc, 2).map_err(|_| Halt::PcOutOfRange {
+                pc: self.pc,
+                backtrace: self.backtrace(),
+            })?,
+        };
+        let (hi, lo) = (code[0], code[1]);
+        let opcode = ((hi as u16) << 8) + lo as u16;
+        // Recorded before decoding, so an unknown opcode ends the trace.
+        if self.trace_depth > 0 {
+            if self.trace.len() == self.trace_depth {
+                self.trace.pop_front();
+            }
+            self.trace.push_back((pc, opcode));
+        }
+        let ins = match Instruction::fetch(code) {
+            Some(ins) => ins,
+            None => return Err(self.unknown_opcode(opcode)),
+        };
+        trace!("{:02X}{:02X} - {}", hi, lo, ins);
+        if (ins.is_schip() && !self.quirks.schip) || (ins.is_xochip() && !self.quirks.xochip) {
+            return Err(self.unknown_opcode(opcode));
+        }
+        // Registers are only compared afterwards if someone listens.
+        let v = self.events.as_ref().map(|_| self.v);
+        // Only recorded once the instruction ran, so that one which halts
+        // can't be undone.
+        let before = if self.history_depth > 0 {
+            self.written.clear();
+            Some(self.state())
+        } else {
+            None
+        };
+        if self.pre_hook.is_some() {
+            let state = self.state();
+            if let Some(hook) = self.pre_hook.as_mut() {
+                (hook.0)(&state, &ins);
+            }
+        }
+        let res = self.execute(ins, ram, fb, inp)?;
+
+        // Determine the next `pc`.
+        match res {
+            Next => {
+                self.pc = self.pc.wrapping_add(ins.size());
+            }
+            Skip => {
+                let next = self.pc.wrapping_add(2);
+                self.pc = next.wrapping_add(self.size_at(ram, next));
+            }
+            // Nothing can change anymore once a jump lands on itself,
+            // which is how many ROMs end.
+            Jump(loc) if loc == self.pc && matches!(ins, Jp(_) | JpV0(_)) => {
+                self.halted = true;
+                return Err(Halt::SpinLoop(loc));
+            }
+            Jump(loc) => {
+                self.pc = loc;
+            }
+        }
+        self.cycles += 1;
+        self.last = Some(ins);
+        if let Some(profile) = self.profile.as_mut() {
+            *profile.entry(ins.pattern()).or_insert(0) += 1;
+        }
+        if let Some(state) = before {
+            if self.history.len() == self.history_depth {
+                self.history.pop_front();
+            }
+            let mut changes = std::mem::take(&mut self.written);
+            changes.extend(self.changes_since(&state));
+            self.history.push_back(Record {
+                pc: state.pc,
+                last: state.last,
+                changes,
+            });
+        }
+        if self.post_hook.is_some() {
+            let state = self.state();
+            if let Some(hook) = self.post_hook.as_mut() {
+                (hook.0)(&state, &ins, res);
+            }
+        }
+        if let Some(before) = v {
+            self.emit(CpuEvent::InstructionExecuted { pc, opcode });
+            for (reg, &old) in before.iter().enumerate() {
+                let value = self.v[reg];
+                if value != old {
+                    self.emit(CpuEvent::RegisterWritten {
+                        reg: reg as u8,
+                        value,
+                    });
+                }
+            }
+            let sound = self.st.get() > 0;
+            if sound != self.sound {
+                self.sound = sound;
+                self.emit(CpuEvent::SoundStateChanged { on: sound });
+            }
+        }
+        self.dump();
+        Ok(Step {
+            pc,
+            opcode,
+            ins,
+            res,
+        })
+    }
+
+    /// Execute `ins`, telling how `pc` moves on.
+    fn execute(
+        &mut self,
+        ins: Instruction,
+        ram: &mut Ram,
+        fb: &mut FrameBuffer,
+        inp: &mut Box<dyn Input>,
+    ) -> Result<Res, Halt> {
+        let res = match ins {
+            Cls => {
+                fb.clear();
+                Next
+            }
+            Ret => {
+                if self.sp == 0 {
+                    return Err(Halt::StackUnderflow {
+                        pc: self.pc,
+                        backtrace: self.backtrace(),
+                    });
+                }
+                let pc = self.stack[self.sp as usize - 1];
+                self.sp -= 1;
+                Jump(pc)
+            }
+            Exit => return Err(Halt::Exit),
+            Scd(n) => {
+                fb.scroll_down(self.scroll_amount(n));
+                Next
+            }
+            Scr => {
+                fb.scroll_right(self.scroll_amount(4));
+                Next
+            }
+            Scl => {
+                fb.scroll_left(self.scroll_amount(4));
+                Next
+            }
+            Low => {
+                self.set_resolution(fb, Resolution::Low);
+                Next
+            }
+            High => {
+                self.set_resolution(fb, Resolution::High);
+                Next
+            }
+            Sys(nnn) => Jump(nnn),
+            Jp(nnn) => Jump(nnn),
+            Call(nnn) => {
+                if self.sp as usize == self.stack.len() {
+                    return Err(Halt::StackOverflow {
+                        pc: self.pc,
+                        backtrace: self.backtrace(),
+                    });
+                }
+                // Return to the instruction after the CALL.
+                self.stack[self.sp as usize] = self.pc.wrapping_add(2);
+                self.callees[self.sp as usize] = nnn;
+                self.sp += 1;
+                Jump(nnn)
+            }
+            SeVxByte(x, kk) => {
+                if self.v[idx(x)] == kk {
+                    Skip
+                } else {
+                    Next
+                }
+            }
+            SneVxByte(x, kk) => {
+                if self.v[idx(x)] != kk {
+                    Skip
+                } else {
+                    Next
+                }
+            }
+            SeVxVy(x, y) => {
+                if self.v[idx(x)] == self.v[idx(y)] {
+                    Skip
+                } else {
+                    Next
+                }
+            }
+            LdVxByte(x, kk) => {
+                self.v[idx(x)] = kk;
+                Next
+            }
+            AddVxByte(x, kk) => {
+                let x = idx(x);
+                self.v[x] = self.v[x].wrapping_add(kk);
                 Next
             }
-            (0x8, x, y, 0x0) => {
-                trace!("8xy0 - LD V{} V{}", x, y);
+            LdVxVy(x, y) => {
                 self.v[idx(x)] = self.v[idx(y)];
                 Next
             }
-            (0x8, x, y, 0x1) => {
-                trace!("8xy1 - OR V{} V{}", x, y);
+            OrVxVy(x, y) => {
                 self.v[idx(x)] |= self.v[idx(y)];
+                self.logic_vf();
+                Next
+            }
+            AndVxVy(x, y) => {
+                self.v[idx(x)] &= self.v[idx(y)];
+                self.logic_vf();
+                Next
+            }
+            XorVxVy(x, y) => {
+                self.v[idx(x)] ^= self.v[idx(y)];
+                self.logic_vf();
+                Next
+            }
+            AddVxVy(x, y) => {
+                let (val, overflow) = self.v[idx(x)].overflowing_add(self.v[idx(y)]);
+                self.v[0xf] = overflow as u8;
+                self.v[idx(x)] = val;
+                Next
+            }
+            SubVxVy(x, y) => {
+                let vx = self.v[idx(x)];
+                let vy = self.v[idx(y)];
+                let (val, borrow) = vx.overflowing_sub(vy);
+                self.v[0xf] = !borrow as u8;
+                self.v[idx(x)] = val;
+                Next
+            }
+            ShrVxVy(x, y) => {
+                let src = self.v[idx(self.shift_source(x, y))];
+                self.v[0xf] = src & 0x1;
+                self.v[idx(x)] = src >> 1;
+                Next
+            }
+            SubnVxVy(x, y) => {
+                let vx = self.v[idx(x)];
+                let vy = self.v[idx(y)];
+                let (val, borrow) = vy.overflowing_sub(vx);
+                self.v[0xf] = !borrow as u8;
+                self.v[idx(x)] = val;
+                Next
+            }
+            ShlVxVy(x, y) => {
+                let src = self.v[idx(self.shift_source(x, y))];
+                self.v[0xf] = src >> 7;
+                self.v[idx(x)] = src << 1;
+                Next
+            }
+            SneVxVy(x, y) => {
+                if self.v[idx(x)] != self.v[idx(y)] {
+                    Skip
+                } else {
+                    Next
+                }
+            }
+            LdI(nnn) => {
+                self.i = nnn;
+                Next
+            }
+            JpV0(nnn) => {
+                let x = if self.quirks.jump_uses_vx {
+                    (nnn >> 8) as u8
+                } else {
+                    0
+                };
+                Jump(nnn + self.v[idx(x)] as u16)
+            }
+            RndVxByte(x, kk) => {
+                let rnd: u8 = self.rng.gen();
+                self.v[idx(x)] = rnd & kk;
+                Next
+            }
+            // Wait for the next frame, like the COSMAC VIP waited for vblank.
+            Drw(..) if self.quirks.display_wait && self.drawn_frame == Some(self.frame()) => {
+                Jump(self.pc)
+            }
+            Drw(x, y, n) => {
+                if self.quirks.display_wait {
+                    self.drawn_frame = Some(self.frame());
+                }
+                let (vx, vy) = self.origin(x, y);
+                let (height, wide) = self.sprite_size(n);
+                let size = height * if wide { 2 } else { 1 };
+                // With both XO-CHIP planes selected, the sprite of the first
+                // plane is followed by the one of the second.
+                let both = self.planes == 3;
+                let mut since = 0;
+                let mut vf = 0;
+                for plane in [1, 2].iter().filter(|p| self.planes & **p != 0) {
+                    let wrapped;
+                    let bytes = match ram.read_range(self.at_i(ram, since), size) {
+                        Ok(bytes) => bytes,
+                        Err(_) => {
+                            wrapped = (since..since + size)
+                                .map(|n| self.load(ram, self.at_i(ram, n)))
+                                .collect::<Result<Vec<u8>, _>>()?;
+                            &wrapped[..]
+                        }
+                    };
+                    trace!("V{}={}, V{}={}, bytes={:?}", x, vx, y, vy, bytes);
+                    if both {
+                        fb.set_planes(*plane);
+                    }
+                    vf |= self.draw_sprite(fb, vx, vy, bytes, wide);
+                    since += size;
+                }
+                if self.i as usize + since > ram.size() {
+                    warn!("Sprite at I={:#05X} wrapped around the end of RAM", self.i);
+                }
+                if both {
+                    fb.set_planes(self.planes);
+                }
+                self.v[0xf] = vf;
+                self.emit(CpuEvent::Draw { x: vx, y: vy });
+                Next
+            }
+            SkpVx(x) => {
+                if inp.is_pressed(self.v[idx(x)]) {
+                    Skip
+                } else if let Some(key) = self.key(inp) {
+                    if key.0 == self.v[idx(x)] {
+                        self.key = None;
+                        Skip
+                    } else {
+                        Next
+                    }
+                } else {
+                    Next
+                }
+            }
+            SknpVx(x) => {
+                if inp.is_pressed(self.v[idx(x)]) {
+                    Next
+                } else if let Some(key) = self.key(inp) {
+                    if key.0 == self.v[idx(x)] {
+                        self.key = None;
+                        Next
+                    } else {
+                        Skip
+                    }
+                } else {
+                    Skip
+                }
+            }
+            LdVxDt(x) => {
+                self.v[idx(x)] = self.dt.get();
+                Next
+            }
+            // Wait for a key to be pressed and released.
+            LdVxK(x) => match self.wait_key {
+                Some(k) if !inp.is_pressed(k) => {
+                    debug!("Got {:?}", k);
+                    self.v[idx(x)] = k;
+                    self.wait_key = None;
+                    Next
+                }
+                Some(_) => Jump(self.pc),
+                None => {
+                    if let Some(k) = (0..16).find(|k| inp.is_pressed(*k)) {
+                        self.wait_key = Some(k);
+                        Jump(self.pc)
+                    } else if let Some(c) = inp.poll().or_else(|| self.key.take()) {
+                        // Inputs only telling presses can't tell releases either,
+                        // so the key is taken as released right away.
+                        debug!("Got {:?}", c);
+                        self.v[idx(x)] = c.0;
+                        Next
+                    } else {
+                        Jump(self.pc)
+                    }
+                }
+            },
+            LdDtVx(x) => {
+                self.dt.set(self.v[idx(x)]);
+                Next
+            }
+            LdStVx(x) => {
+                self.st.set(self.v[idx(x)]);
+                Next
+            }
+            AddIVx(x) => {
+                self.i = self.i.wrapping_add(self.v[idx(x)] as u16);
+                if self.quirks.i_overflow_sets_vf {
+                    self.v[0xf] = (self.i > 0xFFF) as u8;
+                }
+                Next
+            }
+            LdFVx(x) => {
+                self.i = fontaddr(ram.font_base(), self.v[idx(x)]);
+                Next
+            }
+            LdPatternI => {
+                let mut pattern = [0; 16];
+                for (n, b) in pattern.iter_mut().enumerate() {
+                    *b = self.load(ram, self.at_i(ram, n))?;
+                }
+                self.pattern = Some(pattern);
+                Next
+            }
+            LdILong(nnnn) => {
+                self.i = nnnn;
+                Next
+            }
+            Plane(n) => {
+                self.planes = n & 0x3;
+                fb.set_planes(self.planes);
+                Next
+            }
+            LdHfVx(x) => {
+                self.i = bigfontaddr(self.v[idx(x)]);
+                Next
+            }
+            LdBVx(x) => {
+                let vx = self.v[idx(x)];
+                self.store(ram, self.at_i(ram, 0), vx / 100 % 10)?;
+                self.store(ram, self.at_i(ram, 1), vx / 10 % 10)?;
+                self.store(ram, self.at_i(ram, 2), vx % 10)?;
+                Next
+            }
+            LdIVx(x) => {
+                for n in 0..x + 1 {
+                    self.store(ram, self.at_i(ram, idx(n)), self.v[idx(n)])?;
+                }
+                self.increment_i(x);
+                Next
+            }
+            LdVxI(x) => {
+                for n in 0..x + 1 {
+                    self.v[idx(n)] = self.load(ram, self.at_i(ram, idx(n)))?;
+                }
+                self.increment_i(x);
+                Next
+            }
+            LdRVx(x) => {
+                for n in 0..=x.min(7) {
+                    self.rpl[idx(n)] = self.v[idx(n)];
+                }
                 Next
             }
-            (0x8, x, y, 0x2) => {
-                trace!("8xy2 - AND V{} V{}", x, y);
-                self.v[idx(x)] &= self.v[idx(y)];
-                Next
+            LdVxR(x) => {
+                for n in 0..=x.min(7) {
+                    self.v[idx(n)] = self.rpl[idx(n)];
+                }
+                Next
+            }
+        };
+
+        Ok(res)
+    }
+
+    /// Register shifted by `8xy6`/`8xyE`.
+    fn shift_source(&self, x: u8, y: u8) -> u8 {
+        if self.quirks.shift_uses_vy {
+            y
+        } else {
+            x
+        }
+    }
+
+    /// Flag of `8xy1`/`8xy2`/`8xy3`.
+    fn logic_vf(&mut self) {
+        if self.quirks.reset_vf {
+            self.v[0xf] = 0;
+        }
+    }
+
+    /// Move `I` past `V0` to `Vx` after `Fx55`/`Fx65`.
+    fn increment_i(&mut self, x: u8) {
+        if self.quirks.increment_i_on_store {
+            self.i = self.i.wrapping_add(x as u16 + 1);
+        }
+    }
+
+    /// Size of the instruction at `addr` to skip over, XO-CHIP `F000 nnnn`
+    /// being the only one of 4 bytes.
+    fn size_at(&self, ram: &Ram, addr: u16) -> u16 {
+        if self.quirks.xochip && ram.read_range(addr, 2).ok() == Some(&[0xF0, 0x00][..]) {
+            4
+        } else {
+            2
+        }
+    }
+
+    /// Call `sink` with what the CPU does from now on, `None` to stop.
+    pub fn set_event_sink(&mut self, sink: Option<Box<dyn FnMut(CpuEvent)>>) {
+        self.events = sink.map(EventSink);
+    }
+
+    /// Call `hook` before each instruction from now on, `None` to stop.
+    pub fn set_pre_hook(&mut self, hook: Option<Box<PreHook>>) {
+        self.pre_hook = hook.map(Hook);
+    }
+
+    /// Call `hook` after each instruction from now on, `None` to stop.
+    pub fn set_post_hook(&mut self, hook: Option<Box<PostHook>>) {
+        self.post_hook = hook.map(Hook);
+    }
+
+    fn emit(&mut self, event: CpuEvent) {
+        if let Some(sink) = self.events.as_mut() {
+            (sink.0)(event);
+        }
+    }
+
+    fn key(&mut self, inp: &mut Box<dyn Input>) -> Option<Key> {
+        inp.poll().or(self.key).inspect(|k| {
+            debug!("receiving key {:?}", k);
+            self.key = Some(*k);
+        })
+    }
+
+    pub fn dump(&self) {
+        trace!(
+            " v{:?} i={}({:x}) stack={:?} sp={} pc={}({:x}) dt={} cycles={}",
+            self.v,
+            self.i,
+            self.i,
+            self.stack,
+            self.sp,
+            self.pc,
+            self.pc,
+            self.dt,
+            self.cycles
+        );
+    }
+}
+
+/// Most bytes of a ROM loaded at 0x200 into the 4KB of RAM.
+pub const MAX_ROM_SIZE: usize = RAM_SIZE - DEFAULT_START_ADDR as usize;
+
+/// Identifies a ROM build, e.g. for bug reports and `romdb`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RomInfo {
+    /// Size in bytes.
+    pub len: usize,
+    /// SHA-1 in lowercase hex.
+    pub hash: String,
+}
+
+impl RomInfo {
+    pub fn of(rom: &[u8]) -> Self {
+        RomInfo {
+            len: rom.len(),
+            hash: romdb::sha1_hex(rom),
+        }
+    }
+}
+
+impl std::fmt::Display for RomInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} bytes, SHA-1 {}", self.len, self.hash)
+    }
+}
+
+/// Sanity check of a ROM by `validate_rom`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RomReport {
+    /// Size in bytes.
+    pub size: usize,
+    /// Offset and value of the first word which isn't an instruction,
+    /// `None` if every word is one.
+    pub first_illegal: Option<(usize, u16)>,
+    /// Whether the ROM fits in RAM, i.e. is at most `MAX_ROM_SIZE` bytes.
+    pub fits: bool,
+}
+
+impl std::fmt::Display for RomReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} bytes", self.size)?;
+        if !self.fits {
+            write!(f, ", more than {} bytes that fit in RAM", MAX_ROM_SIZE)?;
+        }
+        match self.first_illegal {
+            Some((offset, word)) => {
+                write!(f, ", illegal word {:04X} at offset {:#X}", word, offset)
+            }
+            None => write!(f, ", every word is an instruction"),
+        }
+    }
+}
+
+/// Check `rom` before running it, e.g. to catch a wrong file being loaded.
+/// Sprites and other data between instructions are often illegal words too,
+/// so `first_illegal` is a hint rather than an error.
+pub fn validate_rom(rom: &[u8]) -> RomReport {
+    let mut first_illegal = None;
+    let mut pos = 0;
+    while pos + 1 < rom.len() {
+        match Instruction::fetch(&rom[pos..]) {
+            Some(ins) => pos += ins.size() as usize,
+            None => {
+                first_illegal = Some((pos, u16::from_be_bytes([rom[pos], rom[pos + 1]])));
+                break;
             }
-            (0x8, x, y, 0x3) => {
-                trace!("8xy3 - XOR V{} V{}", x, y);
-                self.v[idx(x)] ^= self.v[idx(y)];
-                Next
+        }
+    }
+    RomReport {
+        size: rom.len(),
+        first_illegal,
+        fits: rom.len() <= MAX_ROM_SIZE,
+    }
+}
+
+/// Bytes of RAM, addressed from 0x000 to 0xFFF.
+const RAM_SIZE: usize = 0x1000;
+
+/// Bytes of RAM with XO-CHIP, whose `F000 nnnn` addresses 16 bits.
+const XOCHIP_RAM_SIZE: usize = 0x10000;
+
+/// Contents of RAM saved by `Ram::snapshot`, e.g. for save states.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RamSnapshot {
+    buf: Box<[u8]>,
+}
+
+impl RamSnapshot {
+    /// Saved bytes from address 0.
+    pub fn bytes(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+/// Chip-8 RAM.
+#[derive(Debug, Clone)]
+pub struct Ram {
+    /// Chip-8 has 0x1000 (4096) bytes of RAM.
+    buf: Vec<u8>,
+    /// Address ROMs are loaded at.
+    start: u16,
+    /// Last loaded ROM, for `reload`.
+    rom: Vec<u8>,
+    /// Address `rom` was loaded at.
+    rom_at: u16,
+    /// Identity of `rom`, `None` until one is loaded.
+    rom_info: Option<RomInfo>,
+    /// 4x5 font and the address it's loaded at.
+    font: [[u8; 5]; 16],
+    font_base: u16,
+}
+
+impl Default for Ram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ram {
+    /// 4KB of RAM, empty until a ROM is loaded.
+    pub fn new() -> Self {
+        Ram::with_size(RAM_SIZE)
+    }
+
+    /// RAM with the font and `program` loaded at 0x200, e.g. to hand
+    /// assembled code to `Chip8Builder::ram` in a test.
+    pub fn from_program(program: &[u8]) -> Result<Self, Error> {
+        let mut ram = Ram::new();
+        ram.load_bytes(program)?;
+        Ok(ram)
+    }
+
+    fn with_size(size: usize) -> Self {
+        Ram {
+            buf: vec![0; size],
+            start: DEFAULT_START_ADDR,
+            rom: Vec::new(),
+            rom_at: DEFAULT_START_ADDR,
+            rom_info: None,
+            font: Font::default().glyphs(),
+            font_base: 0,
+        }
+    }
+
+    /// Load Chip8 ROM into memory at the start address, returning its size.
+    pub fn load<S: Read>(&mut self, stream: S) -> Result<usize, Error> {
+        self.load_at(stream, self.start)
+    }
+
+    /// Load Chip8 ROM into memory at `offset` rather than the start address,
+    /// e.g. to patch code into another region, returning its size. Reading
+    /// stops one byte past what fits, so an endless stream fails as well.
+    pub fn load_at<S: Read>(&mut self, stream: S, offset: u16) -> Result<usize, Error> {
+        let max = self.buf.len().saturating_sub(offset as usize);
+        let mut rom = Vec::new();
+        stream.take(max as u64 + 1).read_to_end(&mut rom)?;
+        self.load_bytes_at(&rom, offset)
+    }
+
+    /// Same as `load` for a ROM already in memory, e.g. by `include_bytes!`.
+    pub fn load_bytes(&mut self, rom: &[u8]) -> Result<usize, Error> {
+        self.load_bytes_at(rom, self.start)
+    }
+
+    /// Same as `load_at` for a ROM already in memory.
+    pub fn load_bytes_at(&mut self, rom: &[u8], offset: u16) -> Result<usize, Error> {
+        self.load_fontset();
+        let start = offset as usize;
+        let max = self.buf.len().saturating_sub(start);
+        if rom.len() > max {
+            return Err(Error::RomTooLarge {
+                size: rom.len(),
+                max,
+            });
+        }
+        self.buf[start..start + rom.len()].copy_from_slice(rom);
+        self.rom = rom.to_vec();
+        self.rom_at = offset;
+        self.rom_info = Some(RomInfo::of(rom));
+
+        Ok(rom.len())
+    }
+
+    /// Size of RAM in bytes.
+    pub fn size(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Byte at `addr`.
+    pub fn read(&self, addr: u16) -> Result<u8, Error> {
+        self.buf
+            .get(addr as usize)
+            .copied()
+            .ok_or(Error::AddressOutOfRange(addr as usize))
+    }
+
+    /// Big endian word at `addr`, e.g. an opcode.
+    pub fn read_word(&self, addr: u16) -> Result<u16, Error> {
+        let bytes = self.read_range(addr, 2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// `len` bytes starting at `addr`, all of which must be in RAM.
+    pub fn read_range(&self, addr: u16, len: usize) -> Result<&[u8], Error> {
+        let start = addr as usize;
+        let end = start + len;
+        if end > self.buf.len() {
+            return Err(Error::AddressOutOfRange(self.buf.len().max(start)));
+        }
+        Ok(&self.buf[start..end])
+    }
+
+    /// Overwrite the byte at `addr`.
+    pub fn write(&mut self, addr: u16, val: u8) -> Result<(), Error> {
+        match self.buf.get_mut(addr as usize) {
+            Some(b) => {
+                *b = val;
+                Ok(())
             }
-            (0x8, x, y, 0x4) => {
-                trace!("8xy4 - ADD V{} V{}", x, y);
-                let xy = self.v[idx(x)] as u16 + self.v[idx(y)] as u16;
-                if xy > 0xff {
-                    self.v[0xf] = 1;
-                } else {
-                    self.v[0xf] = 0;
+            None => Err(Error::AddressOutOfRange(addr as usize)),
+        }
+    }
+
+    /// Copy of the whole RAM to `restore` later.
+    pub fn snapshot(&self) -> RamSnapshot {
+        RamSnapshot {
+            buf: self.buf.clone().into_boxed_slice(),
+        }
+    }
+
+    /// Put back the contents saved by `snapshot`.
+    pub fn restore(&mut self, snapshot: &RamSnapshot) {
+        self.buf.clear();
+        self.buf.extend_from_slice(&snapshot.buf);
+    }
+
+    /// Classic hexdump of `len` bytes from `start`, 16 bytes per line with
+    /// their address and printable ASCII. The range is clamped to RAM.
+    pub fn hexdump(&self, start: u16, len: usize) -> String {
+        let start = (start as usize).min(self.buf.len());
+        let end = start.saturating_add(len).min(self.buf.len());
+        let mut out = String::new();
+        for (n, line) in self.buf[start..end].chunks(16).enumerate() {
+            out += &format!("{:04X}:", start + n * 16);
+            for i in 0..16 {
+                match line.get(i) {
+                    Some(b) => out += &format!(" {:02X}", b),
+                    None => out += "   ",
                 }
-                self.v[idx(x)] = (xy & 0xff) as u8;
-                Next
             }
-            (0x8, x, y, 0x5) => {
-                let vx = self.v[idx(x)];
-                let vy = self.v[idx(y)];
-                trace!("8xy5 - SUB V{}={} V{}={}", x, vx, y, vy);
-                let (val, overflow) = vx.overflowing_sub(vy);
-                if !overflow {
-                    self.v[0xf] = 1;
+            out += "  |";
+            for &b in line {
+                out.push(if b.is_ascii_graphic() || b == b' ' {
+                    b as char
                 } else {
-                    self.v[0xf] = 0;
-                }
-                self.v[idx(x)] = val;
-                Next
-            }
-            (0x8, x, y, 0x6) => {
-                trace!("8xy6 - SHR V{} V{}", x, y);
-                self.v[0xf] = self.v[idx(x)] & 0x1;
-                self.v[idx(x)] /= 2;
-                Next
+                    '.'
+                });
             }
-            (0x8, x, y, 0x7) => {
-                let vx = self.v[idx(x)];
-                let vy = self.v[idx(y)];
-                trace!("8xy7 - SUBN V{}={} V{}={}", x, vx, y, vy);
-                let (val, overflow) = vy.overflowing_sub(vx);
+            out += "|\n";
+        }
+        out
+    }
 
-                if !overflow {
-                    self.v[0xf] = 1;
-                } else {
-                    self.v[0xf] = 0;
-                }
-                self.v[idx(x)] = val;
-                Next
-            }
-            (0x8, x, y, 0xE) => {
-                trace!("8xyE - SHL V{} V{}", x, y);
-                self.v[0xf] = self.v[idx(x)] >> 7;
-                self.v[idx(x)] = self.v[idx(x)].overflowing_mul(2).0;
-                Next
-            }
-            (0x9, x, y, 0x0) => {
-                trace!("SNE V{}, V{}", x, y);
-                if self.v[idx(x)] != self.v[idx(y)] {
-                    Skip
-                } else {
-                    Next
+    /// Put RAM back to how it was right after the last `load`.
+    pub fn reload(&mut self) {
+        for b in self.buf.iter_mut() {
+            *b = 0;
+        }
+        self.load_fontset();
+        let start = self.rom_at as usize;
+        self.buf[start..start + self.rom.len()].copy_from_slice(&self.rom);
+    }
+
+    /// Address ROMs are loaded at.
+    pub fn start_addr(&self) -> u16 {
+        self.start
+    }
+
+    /// Size and hash of the last loaded ROM.
+    pub fn rom_info(&self) -> Option<&RomInfo> {
+        self.rom_info.as_ref()
+    }
+
+    /// Use `glyphs` at `base` as the 4x5 font, also after `reload`. The
+    /// font has to end before the start address and stay clear of the
+    /// big font at `BIGFONT_ADDR`.
+    pub fn load_fontset_from(&mut self, glyphs: &[[u8; 5]; 16], base: u16) -> Result<(), Error> {
+        let end = base as usize + glyphs.len() * 5;
+        if end > self.buf.len() {
+            return Err(Error::AddressOutOfRange(end - 1));
+        }
+        let bigfont = BIGFONT_ADDR as usize..BIGFONT_ADDR as usize + 16 * 10;
+        if end > self.start as usize || ((base as usize) < bigfont.end && end > bigfont.start) {
+            return Err(Error::FontOverlaps(base));
+        }
+        self.font = *glyphs;
+        self.font_base = base;
+        self.load_fontset();
+        Ok(())
+    }
+
+    /// Address of the 4x5 font.
+    pub fn font_base(&self) -> u16 {
+        self.font_base
+    }
+
+    fn load_fontset(&mut self) {
+        let base = self.font_base as usize;
+        for (n, glyph) in self.font.iter().enumerate() {
+            self.buf[base + n * 5..base + n * 5 + 5].copy_from_slice(glyph);
+        }
+
+        let bigfontset = vec![
+            0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+            0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+            0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+            0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+            0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+            0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+            0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+            0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+            0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+            0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+            0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+            0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+        ];
+        let base = BIGFONT_ADDR as usize;
+        self.buf[base..base + bigfontset.len()].copy_from_slice(&bigfontset);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Coordinates of the pixels which are on, column by column.
+    fn lit(fb: &FrameBuffer) -> Vec<(usize, usize)> {
+        let mut lit = Vec::new();
+        for (x, col) in fb.pixels().iter().enumerate() {
+            for (y, &p) in col.iter().enumerate() {
+                if p != 0 {
+                    lit.push((x, y));
                 }
             }
-            (0xA, n1, n2, n3) => {
-                self.i = addr(n1, n2, n3);
-                trace!("Annn - LD I, {}", self.i);
-                Next
-            }
-            (0xB, n1, n2, n3) => {
-                let i = addr(n1, n2, n3) + self.v[0] as u16;
-                trace!("Bnnn - JP V0, {:x}", i);
-                Jump(i)
-            }
-            (0xC, x, k1, k2) => {
-                let rnd: u8 = random();
-                let kk = var(k1, k2);
-                trace!("Cxkk - RND V{} {}", x, kk);
-                self.v[idx(x)] = rnd & kk;
-                Next
-            }
-            (0xD, x, y, n) => {
-                let vx = self.v[idx(x)];
-                let vy = self.v[idx(y)];
-                let since = self.i as usize;
-                let until = since + idx(n);
-                let bytes = (&ram.buf[since..until]).to_vec();
-                trace!(
-                    "Dxyn - DRW V{}={}, V{}={}, nibble={}, bytes={:?}",
-                    x,
-                    vx,
-                    y,
-                    vy,
-                    n,
-                    bytes
-                );
-                self.v[0xf] = self.draw(io, vx, vy, bytes).unwrap();
-                Next
-            }
-            (0xE, x, 0x9, 0xE) => {
-                trace!("Ex9E - SKP V{}={}", x, self.v[idx(x)]);
-                if let Some(key) = self.key(inp) {
-                    if key.0 == self.v[idx(x)] {
-                        self.key = None;
-                        Skip
-                    } else {
-                        Next
-                    }
-                } else {
-                    Next
-                }
+        }
+        lit
+    }
+
+    #[test]
+    fn frame_buffer_xors_sprites() {
+        let mut fb = FrameBuffer::new();
+        assert_eq!(fb.draw(2, 1, &[0b1100_0000]), 0);
+        assert_eq!(lit(&fb), vec![(2, 1), (3, 1)]);
+        // Overlapping pixel 3 is turned off, which is a collision.
+        assert_eq!(fb.draw(3, 1, &[0b1100_0000]), 1);
+        assert_eq!(lit(&fb), vec![(2, 1), (4, 1)]);
+        assert_eq!(fb.draw(10, 10, &[0b1000_0000]), 0);
+    }
+
+    #[test]
+    fn frame_buffer_clips_at_edges() {
+        let mut fb = FrameBuffer::new();
+        assert_eq!(fb.draw(62, 31, &[0xFF, 0xFF]), 0);
+        assert_eq!(lit(&fb), vec![(62, 31), (63, 31)]);
+    }
+
+    #[test]
+    fn frame_buffer_clear() {
+        let mut fb = FrameBuffer::new();
+        fb.draw(0, 0, &[0xFF; 4]);
+        fb.clear();
+        assert!(lit(&fb).is_empty());
+        // Nothing is on after a clear, so redrawing doesn't collide.
+        assert_eq!(fb.draw(0, 0, &[0xFF]), 0);
+    }
+
+    #[test]
+    fn pc_past_the_end_of_ram_halts() {
+        let mut chip8 = Chip8::builder().build();
+        chip8.ram.load(&[0x1F, 0xFC][..]).unwrap();
+        chip8.ram.buf[0xFFC..].copy_from_slice(&[0x6A, 0x01, 0x6B, 0x02]);
+        // JP 0xFFC, then LD VA, 1 and LD VB, 2 as the last instruction.
+        for _ in 0..3 {
+            chip8.tick().unwrap();
+        }
+        assert_eq!((chip8.cpu.v[0xA], chip8.cpu.v[0xB]), (1, 2));
+        let halt = chip8.tick().unwrap_err();
+        assert_eq!(
+            halt,
+            Halt::PcOutOfRange {
+                pc: 0x1000,
+                backtrace: vec![]
             }
-            (0xE, x, 0xA, 0x1) => {
-                trace!("ExA1 - SKNP V{}={}", x, self.v[idx(x)]);
-                if let Some(key) = self.key(inp) {
-                    if key.0 == self.v[idx(x)] {
-                        self.key = None;
-                        Next
-                    } else {
-                        Skip
-                    }
-                } else {
-                    Skip
-                }
+        );
+        assert_eq!(
+            halt.to_string(),
+            "program counter left addressable memory at 0x1000"
+        );
+        assert_eq!(
+            chip8.run(),
+            Halt::PcOutOfRange {
+                pc: 0x1000,
+                backtrace: vec![]
             }
-            (0xF, x, 0x0, 0x7) => {
-                trace!("Fx07 - LD Vx, DT");
-                self.v[idx(x)] = self.dt.get();
-                Next
+        );
+    }
+
+    /// Emulator with `depth` undo records running raw `program` bytes.
+    fn with_history(depth: usize, program: &[u8]) -> Chip8 {
+        let mut chip8 = Chip8::builder().history(depth).build();
+        chip8.ram.load(program).unwrap();
+        chip8
+    }
+
+    #[test]
+    fn step_back_restores_registers_and_ram() {
+        // LD V0, 5; LD I, 0x300; LD [I], V0; CALL 0x208; RET
+        let program = [0x60, 0x05, 0xA3, 0x00, 0xF0, 0x55, 0x22, 0x08, 0x00, 0xEE];
+        let mut chip8 = with_history(8, &program);
+        for _ in 0..4 {
+            chip8.tick().unwrap();
+        }
+        let state = chip8.cpu.state();
+        assert_eq!((state.pc, state.sp, state.i), (0x208, 1, 0x300));
+        assert_eq!(chip8.ram.buf[0x300], 5);
+
+        assert!(chip8.step_back());
+        assert_eq!((chip8.cpu.pc, chip8.cpu.sp), (0x206, 0));
+        assert!(chip8.step_back());
+        assert_eq!(chip8.ram.buf[0x300], 0);
+        assert_eq!(chip8.cpu.i, 0x300);
+        assert!(chip8.step_back());
+        assert!(chip8.step_back());
+        assert_eq!((chip8.cpu.pc, chip8.cpu.v[0], chip8.cpu.i), (0x200, 0, 0));
+        assert_eq!(chip8.cpu.cycle_count(), 0);
+        assert!(!chip8.step_back());
+    }
+
+    #[test]
+    fn step_back_is_bounded_by_depth() {
+        let mut chip8 = with_history(2, &[0x60, 0x01, 0x60, 0x02, 0x60, 0x03]);
+        for _ in 0..3 {
+            chip8.tick().unwrap();
+        }
+        assert!(chip8.step_back());
+        assert!(chip8.step_back());
+        assert!(!chip8.step_back());
+        assert_eq!(chip8.cpu.v[0], 1);
+    }
+
+    #[test]
+    fn step_back_without_history() {
+        let mut chip8 = with_history(0, &[0x60, 0x01]);
+        chip8.tick().unwrap();
+        assert!(!chip8.step_back());
+        assert_eq!(chip8.cpu.v[0], 1);
+    }
+
+    #[test]
+    fn frame_buffer_scrolls() {
+        let mut fb = FrameBuffer::new();
+        fb.draw(0, 0, &[0b1000_0001]);
+        fb.scroll_down(3);
+        assert_eq!(lit(&fb), vec![(0, 3), (7, 3)]);
+        fb.scroll_right(4);
+        assert_eq!(lit(&fb), vec![(4, 3), (11, 3)]);
+        fb.scroll_left(4);
+        assert_eq!(lit(&fb), vec![(0, 3), (7, 3)]);
+    }
+
+    #[test]
+    fn frame_buffer_clears_vacated_pixels() {
+        let mut fb = FrameBuffer::new();
+        fb.draw(60, 28, &[0xF0, 0xF0, 0xF0, 0xF0]);
+        fb.scroll_right(4);
+        // Pixels scrolled past the right edge are gone.
+        assert!(lit(&fb).is_empty());
+        fb.draw(56, 28, &[0xFF, 0xFF, 0xFF, 0xFF]);
+        fb.scroll_down(2);
+        assert_eq!(lit(&fb).len(), 16);
+        for _ in 0..16 {
+            fb.scroll_left(4);
+        }
+        assert!(lit(&fb).is_empty());
+    }
+
+    /// Emulator with SUPER-CHIP instructions enabled running `src`.
+    fn schip(src: &str) -> Chip8 {
+        let mut chip8 = Chip8::builder().build();
+        chip8.cpu.quirks.schip = true;
+        chip8.ram.load(&asm::assemble(src).unwrap()[..]).unwrap();
+        chip8
+    }
+
+    #[test]
+    fn rpl_round_trip() {
+        let src = "
+            LD V0, 1\nLD V1, 2\nLD V2, 3\nLD V3, 4\nLD V4, 5
+            LD R, V4
+            LD V0, 0\nLD V1, 0\nLD V2, 0\nLD V3, 0\nLD V4, 0
+            LD V4, R
+        ";
+        let mut chip8 = schip(src);
+        for _ in 0..6 {
+            chip8.tick().unwrap();
+        }
+        assert_eq!(chip8.cpu.rpl, [1, 2, 3, 4, 5, 0, 0, 0]);
+        for _ in 0..5 {
+            chip8.tick().unwrap();
+        }
+        assert_eq!(&chip8.cpu.v[..5], &[0; 5]);
+        chip8.tick().unwrap();
+        assert_eq!(&chip8.cpu.v[..5], &[1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn rpl_is_capped_at_v7() {
+        let mut chip8 = schip("LD V7, 7\nLD V8, 8\nLD R, VF\nLD V7, 0\nLD V8, 0\nLD VF, R");
+        for _ in 0..6 {
+            chip8.tick().unwrap();
+        }
+        assert_eq!(chip8.cpu.rpl[7], 7);
+        assert_eq!((chip8.cpu.v[7], chip8.cpu.v[8]), (7, 0));
+    }
+
+    #[test]
+    fn rpl_survives_reset() {
+        let mut chip8 = schip("LD V0, 0x42\nLD R, V0");
+        chip8.tick().unwrap();
+        chip8.tick().unwrap();
+        chip8.cpu.reset();
+        assert_eq!(chip8.cpu.state().rpl[0], 0x42);
+    }
+
+    #[test]
+    fn scroll_amounts_follow_the_resolution() {
+        let mut chip8 = schip("HIGH\nLOW");
+        chip8.tick().unwrap();
+        assert_eq!(
+            (chip8.cpu.scroll_amount(3), chip8.cpu.scroll_amount(4)),
+            (3, 4)
+        );
+        // Counted in high resolution pixels, so half as far.
+        chip8.tick().unwrap();
+        assert_eq!(
+            (chip8.cpu.scroll_amount(3), chip8.cpu.scroll_amount(4)),
+            (1, 2)
+        );
+    }
+
+    #[test]
+    fn scroll_is_unknown_without_schip() {
+        let mut chip8 = schip("SCR");
+        chip8.cpu.quirks.schip = false;
+        match chip8.tick() {
+            Err(Halt::UnknownOpcode { opcode: 0x00FB, .. }) => {}
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn dxy0_sprite_size() {
+        let mut cpu = Cpu::with_history(0);
+        cpu.quirks.schip = true;
+        for &lores_dxy0 in [LoresDxy0::Empty, LoresDxy0::Tall, LoresDxy0::Large].iter() {
+            cpu.quirks.lores_dxy0 = lores_dxy0;
+            cpu.res = Resolution::High;
+            assert_eq!(cpu.sprite_size(0), (16, true));
+            assert_eq!(cpu.sprite_size(5), (5, false));
+        }
+        cpu.res = Resolution::Low;
+        cpu.quirks.lores_dxy0 = LoresDxy0::Empty;
+        assert_eq!(cpu.sprite_size(0), (0, false));
+        cpu.quirks.lores_dxy0 = LoresDxy0::Tall;
+        assert_eq!(cpu.sprite_size(0), (16, false));
+        cpu.quirks.lores_dxy0 = LoresDxy0::Large;
+        assert_eq!(cpu.sprite_size(0), (16, true));
+        // Without SUPER-CHIP Dxy0 draws nothing.
+        cpu.quirks.schip = false;
+        assert_eq!(cpu.sprite_size(0), (0, false));
+    }
+
+    #[test]
+    fn dxy0_collides_on_the_right_half() {
+        // Only pixel (15, 0) is on, so only the right 8 pixels can collide.
+        let src = "
+            HIGH
+            LD V0, 0
+            LD I, big
+            DRW V0, V0, 0
+            DRW V0, V0, 0
+            big: .byte 0x00, 0x01
+        ";
+        let mut chip8 = schip(src);
+        for _ in 0..4 {
+            chip8.tick().unwrap();
+        }
+        assert_eq!(chip8.cpu.v[0xF], 0);
+        chip8.tick().unwrap();
+        assert_eq!(chip8.cpu.v[0xF], 1);
+    }
+
+    #[test]
+    fn dxy0_draws_16_rows_of_two_bytes() {
+        let mut chip8 = Chip8::builder().quirks(Quirks::schip()).build();
+        let src = "
+            HIGH
+            LD V0, 8
+            LD I, big
+            DRW V0, V0, 0
+            end: JP end
+            big:
+            .byte 0x80, 0x01
+            .byte 0x00, 0x00
+            .byte 0x00, 0x00
+            .byte 0x00, 0x00
+            .byte 0x00, 0x00
+            .byte 0x00, 0x00
+            .byte 0x00, 0x00
+            .byte 0x00, 0x00
+            .byte 0x00, 0x00
+            .byte 0x00, 0x00
+            .byte 0x00, 0x00
+            .byte 0x00, 0x00
+            .byte 0x00, 0x00
+            .byte 0x00, 0x00
+            .byte 0x00, 0x00
+            .byte 0x01, 0x80
+        ";
+        chip8.ram.load(&asm::assemble(src).unwrap()[..]).unwrap();
+        for _ in 0..4 {
+            chip8.tick().unwrap();
+        }
+        assert_eq!(lit(&chip8.fb), vec![(8, 8), (15, 23), (16, 23), (23, 8)]);
+    }
+
+    #[test]
+    fn both_fonts_fit_before_the_program() {
+        let mut ram = Ram::new();
+        ram.load(&[][..]).unwrap();
+        let small = 0..16 * 5;
+        let big = BIGFONT_ADDR as usize..BIGFONT_ADDR as usize + 16 * 10;
+        assert!(small.end <= big.start);
+        assert!(big.end <= 0x200);
+        // 0 is the first glyph of each font.
+        assert_eq!(&ram.buf[..5], &[0xF0, 0x90, 0x90, 0x90, 0xF0]);
+        // Big 8 is the rows of 0 with a bar in the middle.
+        let addr = bigfontaddr(8) as usize;
+        assert_eq!(
+            &ram.buf[addr..addr + 10],
+            &[0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF]
+        );
+    }
+
+    #[test]
+    fn fx30_points_at_the_big_font() {
+        let mut chip8 = schip("LD V0, 0x0F\nLD V2, 0x1F\nLD HF, V2\nLD F, V0\nLD HF, V0");
+        for _ in 0..3 {
+            chip8.tick().unwrap();
+        }
+        // Only the low nibble selects the digit.
+        assert_eq!(chip8.cpu.i, BIGFONT_ADDR + 0xF * 10);
+        chip8.tick().unwrap();
+        assert_eq!(chip8.cpu.i, 0xF * 5);
+        chip8.tick().unwrap();
+        assert_eq!(chip8.cpu.i, BIGFONT_ADDR + 0xF * 10);
+    }
+
+    #[test]
+    fn timer_counts_down_at_60hz() {
+        let clock = Rc::new(ManualClock::new());
+        let mut timer = DelayTimer::with_clock(clock.clone());
+        timer.set(3);
+        clock.advance(Duration::from_millis(16));
+        assert_eq!(timer.get(), 3);
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(timer.get(), 2);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(timer.get(), 0);
+        // Setting again counts from now.
+        timer.set(1);
+        assert_eq!(timer.get(), 1);
+    }
+
+    #[test]
+    fn rpl_file_is_the_raw_flags() {
+        let mut chip8 = schip("LD V0, 1\nLD V1, 2\nLD V7, 0xFF\nLD R, V7");
+        for _ in 0..4 {
+            chip8.tick().unwrap();
+        }
+        let mut saved = Vec::new();
+        chip8.cpu.save_rpl(&mut saved).unwrap();
+        assert_eq!(saved, vec![1, 2, 0, 0, 0, 0, 0, 0xFF]);
+
+        let mut cpu = Cpu::with_history(0);
+        cpu.load_rpl(&saved[..]).unwrap();
+        assert_eq!(cpu.rpl(), chip8.cpu.rpl());
+    }
+
+    #[test]
+    fn loaded_rpl_is_read_by_fx85() {
+        let mut chip8 = schip("LD VF, R");
+        chip8.cpu.load_rpl(&[8, 7, 6, 5, 4, 3, 2, 1][..]).unwrap();
+        chip8.tick().unwrap();
+        assert_eq!(&chip8.cpu.v[..8], &[8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(chip8.cpu.v[0xF], 0);
+    }
+
+    #[test]
+    fn short_rpl_file_is_rejected() {
+        let mut cpu = Cpu::with_history(0);
+        assert!(cpu.load_rpl(&[1, 2, 3][..]).is_err());
+    }
+
+    /// `Audio` remembering the patterns it was given.
+    #[derive(Default)]
+    struct Patterns(Rc<std::cell::RefCell<Vec<[u8; 16]>>>);
+
+    impl Audio for Patterns {
+        fn play(&mut self) {}
+        fn pause(&mut self) {}
+        fn set_pattern(&mut self, pattern: [u8; 16], rate: u32) {
+            assert_eq!(rate, PATTERN_RATE);
+            self.0.borrow_mut().push(pattern);
+        }
+    }
+
+    #[test]
+    fn f002_loads_the_audio_pattern() {
+        let src = "
+            LD I, pattern
+            AUDIO
+            LD V0, 0xAA
+            LD [I], V0
+            end: JP end
+            pattern: .byte 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08
+                     .byte 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F, 0x10
+        ";
+        let patterns = Patterns::default();
+        let given = Rc::clone(&patterns.0);
+        let mut chip8 = Chip8::builder()
+            .quirks(Quirks {
+                xochip: true,
+                ..Quirks::default()
+            })
+            .audio(Box::new(patterns))
+            .build();
+        chip8.ram.load(&asm::assemble(src).unwrap()[..]).unwrap();
+        assert_eq!(chip8.cpu.pattern(), None);
+        chip8.tick().unwrap();
+        chip8.tick().unwrap();
+        let expected = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+        assert_eq!(chip8.cpu.pattern(), Some(expected));
+        assert_eq!(*given.borrow(), vec![expected]);
+        // The pattern is a copy, which doesn't change with RAM.
+        chip8.tick().unwrap();
+        chip8.tick().unwrap();
+        assert_eq!(chip8.cpu.pattern(), Some(expected));
+        assert_eq!(given.borrow().len(), 1);
+    }
+
+    #[test]
+    fn f002_is_unknown_without_xochip() {
+        let mut chip8 = schip("AUDIO");
+        match chip8.tick() {
+            Err(Halt::UnknownOpcode { opcode: 0xF002, .. }) => {}
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+
+    /// Registers V0 to V3 after four `RND Vx, 0xFF` seeded with `seed`.
+    fn random_bytes(seed: u64) -> [u8; 4] {
+        let mut chip8 = Chip8::builder().build();
+        let src = "RND V0, 0xFF\nRND V1, 0xFF\nRND V2, 0xFF\nRND V3, 0xFF";
+        chip8.ram.load(&asm::assemble(src).unwrap()[..]).unwrap();
+        chip8.cpu.seed_rng(seed);
+        for _ in 0..4 {
+            chip8.tick().unwrap();
+        }
+        let mut v = [0; 4];
+        v.copy_from_slice(&chip8.cpu.v[..4]);
+        v
+    }
+
+    #[test]
+    fn seeded_rnd_is_reproducible() {
+        assert_eq!(random_bytes(7), random_bytes(7));
+        assert_ne!(random_bytes(7), random_bytes(8));
+    }
+
+    #[test]
+    fn rnd_is_masked() {
+        let mut chip8 = Chip8::builder().build();
+        let src = "RND V0, 0x0F\nRND V1, 0x81\nRND V2, 0";
+        chip8.ram.load(&asm::assemble(src).unwrap()[..]).unwrap();
+        for seed in 0..32 {
+            chip8.cpu.pc = 0x200;
+            chip8.cpu.seed_rng(seed);
+            for _ in 0..3 {
+                chip8.tick().unwrap();
             }
-            (0xF, x, 0x0, 0xA) => {
-                trace!("Fx0A - LD Vx, K");
-                let mut pressed = false;
-                if let Some(c) = self.key(inp) {
-                    debug!("Got {:?}", c);
-                    self.v[idx(x)] = c.0;
-                    pressed = true;
-                }
+            assert_eq!(chip8.cpu.v[0] & 0xF0, 0);
+            assert_eq!(chip8.cpu.v[1] & 0x7E, 0);
+            assert_eq!(chip8.cpu.v[2], 0);
+        }
+    }
+
+    #[test]
+    fn long_i_is_four_bytes() {
+        let mut chip8 = Chip8::builder()
+            .quirks(Quirks {
+                xochip: true,
+                ..Quirks::default()
+            })
+            .build();
+        let src = "LD I, LONG 0x1234\nLD V0, 1";
+        chip8.ram.load(&asm::assemble(src).unwrap()[..]).unwrap();
+        chip8.tick().unwrap();
+        assert_eq!((chip8.cpu.pc, chip8.cpu.i), (0x204, 0x1234));
+        chip8.tick().unwrap();
+        assert_eq!(chip8.cpu.v[0], 1);
+    }
+
+    /// PC after running `skip`, which skips, over `next`.
+    fn pc_after_skip(xochip: bool, skip: &str, next: &str) -> u16 {
+        let mut chip8 = Chip8::builder()
+            .quirks(Quirks {
+                xochip,
+                ..Quirks::default()
+            })
+            .build();
+        let src = format!("LD V1, 1\nLD V2, 1\n{}\n{}\nLD V3, 3", skip, next);
+        chip8.ram.load(&asm::assemble(&src).unwrap()[..]).unwrap();
+        for _ in 0..3 {
+            chip8.tick().unwrap();
+        }
+        chip8.cpu.pc
+    }
+
+    #[test]
+    fn skips_over_long_i() {
+        let skips = [
+            "SE V1, 1",
+            "SNE V1, 2",
+            "SE V1, V2",
+            "SNE V0, V1",
+            "SKNP V0",
+        ];
+        for skip in skips.iter() {
+            assert_eq!(pc_after_skip(true, skip, "LD V0, 0"), 0x208, "{}", skip);
+            assert_eq!(
+                pc_after_skip(true, skip, "LD I, LONG 0x1234"),
+                0x20A,
+                "{}",
+                skip
+            );
+        }
+    }
+
+    #[test]
+    fn skips_over_f000_as_two_bytes_without_xochip() {
+        assert_eq!(pc_after_skip(false, "SE V1, 1", ".byte 0xF0, 0x00"), 0x208);
+    }
+
+    /// CPU after `n` instructions of `src` run with `quirks`.
+    fn run_with(quirks: Quirks, src: &str, n: usize) -> Cpu {
+        let mut chip8 = Chip8::builder().quirks(quirks).build();
+        chip8.ram.load(&asm::assemble(src).unwrap()[..]).unwrap();
+        for _ in 0..n {
+            chip8.tick().unwrap();
+        }
+        chip8.cpu
+    }
+
+    #[test]
+    fn quirk_shift() {
+        let src = "LD V0, 3\nLD V1, 0x10\nSHR V0, V1";
+        assert_eq!(run_with(Quirks::chip8(), src, 3).v[0], 0x08);
+        assert_eq!(run_with(Quirks::xochip(), src, 3).v[0], 0x08);
+        assert_eq!(run_with(Quirks::schip(), src, 3).v[0], 0x01);
+        assert_eq!(run_with(Quirks::chip48(), src, 3).v[0], 0x01);
+    }
 
-                if pressed {
-                    Next
-                } else {
-                    Jump(self.pc)
-                }
-            }
-            (0xF, x, 0x1, 0x5) => {
-                trace!("Fx15 - LD DT, Vx");
-                self.dt.set(self.v[idx(x)]);
-                Next
+    #[test]
+    fn quirk_memory() {
+        let src = "LD I, 0x300\nLD [I], V2\nLD V2, [I]";
+        assert_eq!(run_with(Quirks::chip8(), src, 3).i, 0x306);
+        assert_eq!(run_with(Quirks::xochip(), src, 3).i, 0x306);
+        assert_eq!(run_with(Quirks::schip(), src, 3).i, 0x300);
+    }
+
+    #[test]
+    fn quirk_vf_reset() {
+        for &op in ["OR", "AND", "XOR"].iter() {
+            let src = format!("LD VF, 5\n{} V0, V1", op);
+            assert_eq!(run_with(Quirks::chip8(), &src, 2).v[0xF], 0, "{}", op);
+            assert_eq!(run_with(Quirks::schip(), &src, 2).v[0xF], 5, "{}", op);
+            assert_eq!(run_with(Quirks::xochip(), &src, 2).v[0xF], 5, "{}", op);
+        }
+    }
+
+    #[test]
+    fn quirk_jump() {
+        // `JP V0, 0x20A` is `B20A`, so `V2` is added with `jump_uses_vx`.
+        let src = "LD V0, 2\nLD V2, 4\nJP V0, 0x20A";
+        assert_eq!(run_with(Quirks::chip8(), src, 3).pc, 0x20C);
+        assert_eq!(run_with(Quirks::xochip(), src, 3).pc, 0x20C);
+        assert_eq!(run_with(Quirks::schip(), src, 3).pc, 0x20E);
+        assert_eq!(run_with(Quirks::chip48(), src, 3).pc, 0x20E);
+    }
+
+    #[test]
+    fn unknown_opcode_carries_the_trace() {
+        let mut chip8 = Chip8::builder().trace(3).build();
+        chip8
+            .ram
+            .load(&[0x60, 0x01, 0x61, 0x02, 0xF2, 0x01][..])
+            .unwrap();
+        chip8.tick().unwrap();
+        chip8.tick().unwrap();
+        let halt = chip8.tick().unwrap_err();
+        assert_eq!(
+            halt,
+            Halt::UnknownOpcode {
+                pc: 0x204,
+                opcode: 0xF201,
+                trace: vec![(0x200, 0x6001), (0x202, 0x6102)],
+                backtrace: vec![]
             }
-            (0xF, x, 0x1, 0x8) => {
-                trace!("Fx18 - LD ST, Vx");
-                Next
+        );
+        assert_eq!(
+            halt.to_string(),
+            "unknown opcode F201 at 0x204 after [0x200: 6001, 0x202: 6102]"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "clock_hz must be at least 1")]
+    fn clock_of_0_hz_panics() {
+        Chip8::builder().clock_hz(0);
+    }
+
+    #[test]
+    fn exit_halts_under_schip() {
+        assert_eq!(schip("EXIT").tick(), Err(Halt::Exit));
+    }
+
+    #[test]
+    fn exit_is_unknown_without_schip() {
+        match assembled("EXIT").tick() {
+            Err(Halt::UnknownOpcode { pc, opcode, .. }) => {
+                assert_eq!((pc, opcode), (0x200, 0x00FD));
             }
-            (0xF, x, 0x1, 0xE) => {
-                trace!("ADD I, Vx");
-                self.i += self.v[idx(x)] as u16;
-                Next
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn chip48_has_no_schip_instructions() {
+        let mut chip8 = Chip8::builder().quirks(Quirks::chip48()).build();
+        chip8.ram.load(&asm::assemble("HIGH").unwrap()[..]).unwrap();
+        assert_eq!(
+            chip8.tick(),
+            Err(Halt::UnknownOpcode {
+                pc: 0x200,
+                opcode: 0x00FF,
+                trace: vec![],
+                backtrace: vec![]
+            })
+        );
+    }
+
+    #[test]
+    fn quirk_wrap_sprites() {
+        // Row 2 of the sprite at (62, 31) only collides with the dot at
+        // (0, 0) if it wrapped around both edges.
+        let src = "
+            LD V0, 62
+            LD V1, 31
+            LD I, sprite
+            DRW V0, V1, 2
+            LD V0, 0
+            LD I, dot
+            DRW V0, V0, 1
+            sprite: .byte 0xE0, 0xE0
+            dot: .byte 0x80
+        ";
+        assert_eq!(run_with(Quirks::chip8(), src, 7).v[0xF], 0);
+        assert_eq!(run_with(Quirks::schip(), src, 7).v[0xF], 0);
+        assert_eq!(run_with(Quirks::xochip(), src, 7).v[0xF], 1);
+    }
+
+    #[test]
+    fn quirk_display_wait() {
+        let src = "DRW V0, V0, 1\nDRW V0, V0, 1";
+        assert_eq!(run_with(Quirks::schip(), src, 2).pc, 0x204);
+
+        let clock = Rc::new(ManualClock::new());
+        let mut chip8 = Chip8::builder()
+            .quirks(Quirks::chip8())
+            .clock(clock.clone())
+            .build();
+        chip8.ram.load(&asm::assemble(src).unwrap()[..]).unwrap();
+        chip8.tick().unwrap();
+        chip8.tick().unwrap();
+        assert_eq!(chip8.cpu.pc, 0x202);
+        // The next frame lets it draw.
+        clock.advance(Duration::from_millis(17));
+        chip8.tick().unwrap();
+        assert_eq!(chip8.cpu.pc, 0x204);
+    }
+
+    #[test]
+    fn jump_to_itself_halts() {
+        let mut chip8 = Chip8::builder().history(4).build();
+        chip8
+            .ram
+            .load(&asm::assemble("LD V0, 1\nend: JP end").unwrap()[..])
+            .unwrap();
+        chip8.tick().unwrap();
+        assert!(!chip8.cpu.is_halted());
+        assert_eq!(chip8.tick(), Err(Halt::SpinLoop(0x202)));
+        assert!(chip8.cpu.is_halted());
+        let cycles = chip8.cpu.cycle_count();
+        // Nothing runs any more, the PC stays on the jump.
+        for _ in 0..3 {
+            assert_eq!(chip8.tick(), Err(Halt::SpinLoop(0x202)));
+            assert_eq!(chip8.cpu.pc, 0x202);
+        }
+        assert_eq!(chip8.cpu.cycle_count(), cycles);
+        assert_eq!(chip8.run(), Halt::SpinLoop(0x202));
+        assert_eq!(
+            Halt::SpinLoop(0x202).to_string(),
+            "program halted by jumping to itself at 0x202"
+        );
+        // Undoing gets out of the halt.
+        assert!(chip8.step_back());
+        assert!(!chip8.cpu.is_halted());
+    }
+
+    #[test]
+    fn jump_elsewhere_runs_on() {
+        let mut chip8 = Chip8::builder().build();
+        let src = "JP next\nnext: LD V0, 1\nJP 0x200";
+        chip8.ram.load(&asm::assemble(src).unwrap()[..]).unwrap();
+        for _ in 0..6 {
+            chip8.tick().unwrap();
+        }
+        assert!(!chip8.cpu.is_halted());
+        assert_eq!(chip8.cpu.pc, 0x200);
+    }
+
+    #[test]
+    fn call_17_deep_overflows() {
+        let mut chip8 = Chip8::builder().build();
+        chip8
+            .ram
+            .load(&asm::assemble("rec: CALL rec").unwrap()[..])
+            .unwrap();
+        for _ in 0..16 {
+            chip8.tick().unwrap();
+        }
+        assert_eq!(chip8.cpu.sp, 16);
+        let halt = chip8.tick().unwrap_err();
+        match &halt {
+            Halt::StackOverflow { pc, backtrace } => {
+                assert_eq!(*pc, 0x200);
+                assert_eq!(backtrace.len(), 16);
             }
-            (0xF, x, 0x2, 0x9) => {
-                let vx = self.v[idx(x)];
-                trace!("Fx29 - LD F, Vx={}", vx);
-                self.i = fontaddr(vx);
-                Next
+            _ => panic!("unexpected {:?}", halt),
+        }
+        assert!(halt
+            .to_string()
+            .starts_with("stack overflow at 0x200, called from 0x200"));
+        assert_eq!(chip8.cpu.sp, 16);
+    }
+
+    #[test]
+    fn ret_with_empty_stack_underflows() {
+        let mut chip8 = Chip8::builder().build();
+        chip8
+            .ram
+            .load(&asm::assemble("LD V0, 1\nRET").unwrap()[..])
+            .unwrap();
+        chip8.tick().unwrap();
+        let halt = chip8.tick().unwrap_err();
+        assert_eq!(
+            halt,
+            Halt::StackUnderflow {
+                pc: 0x202,
+                backtrace: vec![]
             }
-            (0xF, x, 0x3, 0x3) => {
-                trace!("Fx33 - LD B, Vx");
-                let i = self.i as usize;
-                let vx = self.v[idx(x)];
-                ram.buf[i] = (vx / 100) as u8 % 10;
-                ram.buf[i + 1] = (vx / 10) as u8 % 10;
-                ram.buf[i + 2] = vx % 10;
-                Next
+        );
+        assert!(halt.backtrace().is_empty());
+        assert_eq!(halt.to_string(), "stack underflow at 0x202");
+        assert_eq!(chip8.cpu.sp, 0);
+    }
+
+    #[test]
+    fn eti_660_start_address() {
+        let mut chip8 = Chip8::builder().start_addr(0x600).build();
+        assert_eq!(chip8.cpu.pc, 0x600);
+        // LD V0, 0x42; JP 0x602
+        chip8.ram.load(&[0x60, 0x42, 0x16, 0x02][..]).unwrap();
+        assert_eq!(&chip8.ram.buf[0x600..0x602], &[0x60, 0x42]);
+        assert_eq!(&chip8.ram.buf[0x200..0x202], &[0, 0]);
+        chip8.tick().unwrap();
+        assert_eq!(chip8.cpu.v[0], 0x42);
+        assert_eq!(chip8.tick(), Err(Halt::SpinLoop(0x602)));
+        chip8.cpu.reset();
+        assert_eq!(chip8.cpu.pc, 0x600);
+    }
+
+    #[test]
+    fn ret_lands_after_the_call() {
+        let src = "
+            CALL outer
+            LD V0, 1
+            end: JP end
+            outer: CALL inner
+                   LD V1, 2
+                   RET
+            inner: LD V2, 3
+                   RET
+        ";
+        let cpu = run_with(Quirks::default(), src, 2);
+        assert_eq!((cpu.pc, cpu.sp), (0x20C, 2));
+        assert_eq!(&cpu.stack[..2], &[0x202, 0x208]);
+        assert_eq!(run_with(Quirks::default(), src, 4).pc, 0x208);
+        assert_eq!(run_with(Quirks::default(), src, 6).pc, 0x202);
+        let cpu = run_with(Quirks::default(), src, 7);
+        assert_eq!(&cpu.v[..3], &[1, 2, 3]);
+        assert_eq!(cpu.sp, 0);
+    }
+
+    #[test]
+    fn reset_restarts_like_new() {
+        let src = "
+            LD V0, 0x15
+            LD DT, V0
+            LD ST, V0
+            LD I, target
+            LD [I], V0
+            CALL sub
+            target: LD V1, 1
+            sub: RET
+        ";
+        let mut chip8 = Chip8::builder().build();
+        chip8.ram.load(&asm::assemble(src).unwrap()[..]).unwrap();
+        let fresh = chip8.cpu.state();
+        let ram = chip8.ram.buf.clone();
+        for _ in 0..6 {
+            chip8.tick().unwrap();
+        }
+        assert_eq!((chip8.cpu.sp, chip8.cpu.dt.get()), (1, 0x15));
+        assert_eq!(chip8.ram.buf[0x20C], 0x15);
+
+        chip8.reset().unwrap();
+        assert_eq!(chip8.cpu.state(), fresh);
+        assert_eq!(chip8.ram.buf[0x20C], 0x61);
+        assert!(chip8.ram.buf == ram);
+        // And it runs the same again.
+        for _ in 0..6 {
+            chip8.tick().unwrap();
+        }
+        assert_eq!(chip8.cpu.sp, 1);
+    }
+
+    /// Emulator running `src` with default quirks.
+    fn assembled(src: &str) -> Chip8 {
+        let mut chip8 = Chip8::builder().build();
+        chip8.ram.load(&asm::assemble(src).unwrap()[..]).unwrap();
+        chip8
+    }
+
+    #[test]
+    fn jp_to_itself_is_a_spin_loop() {
+        let mut chip8 = assembled("end: JP end");
+        assert!(matches!(chip8.step(), Err(Halt::SpinLoop(0x200))));
+        assert!(chip8.cpu.is_halted());
+    }
+
+    #[test]
+    fn jp_v0_to_itself_is_a_spin_loop() {
+        // The jump at 0x202 lands on itself only once V0 is 2.
+        let mut chip8 = assembled("LD V0, 2\nJP V0, 0x200");
+        chip8.step().unwrap();
+        assert!(matches!(chip8.step(), Err(Halt::SpinLoop(0x202))));
+        assert_eq!(chip8.cpu.pc, 0x202);
+
+        let mut chip8 = assembled("LD V0, 4\nJP V0, 0x200\nLD V1, 1");
+        chip8.step().unwrap();
+        chip8.step().unwrap();
+        assert_eq!(chip8.cpu.pc, 0x204);
+        assert!(!chip8.cpu.is_halted());
+    }
+
+    #[test]
+    fn hooks_see_every_instruction() {
+        let mut chip8 = assembled("loop: ADD V0, 1\nJP loop");
+        let before = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let after = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let seen = Rc::clone(&before);
+        chip8.cpu.set_pre_hook(Some(Box::new(
+            move |state: &CpuState, ins: &Instruction| {
+                seen.borrow_mut().push((state.pc, state.v[0], *ins));
+            },
+        )));
+        let results = Rc::clone(&after);
+        chip8.cpu.set_post_hook(Some(Box::new(
+            move |state: &CpuState, _: &Instruction, res: Res| {
+                results.borrow_mut().push((state.pc, res));
+            },
+        )));
+        for _ in 0..4 {
+            chip8.step().unwrap();
+        }
+        // The pre hook runs before the add, the post hook after the jump.
+        assert_eq!(
+            *before.borrow(),
+            vec![
+                (0x200, 0, AddVxByte(0, 1)),
+                (0x202, 1, Jp(0x200)),
+                (0x200, 1, AddVxByte(0, 1)),
+                (0x202, 2, Jp(0x200)),
+            ]
+        );
+        assert_eq!(
+            *after.borrow(),
+            vec![
+                (0x202, Next),
+                (0x200, Jump(0x200)),
+                (0x202, Next),
+                (0x200, Jump(0x200)),
+            ]
+        );
+    }
+
+    #[test]
+    fn removed_hooks_are_not_called() {
+        let mut chip8 = assembled("loop: JP loop2\nloop2: JP loop");
+        let count = Rc::new(std::cell::Cell::new(0));
+        let counter = Rc::clone(&count);
+        chip8
+            .cpu
+            .set_pre_hook(Some(Box::new(move |_: &CpuState, _: &Instruction| {
+                counter.set(counter.get() + 1)
+            })));
+        chip8.step().unwrap();
+        chip8.step().unwrap();
+        chip8.cpu.set_pre_hook(None);
+        chip8.step().unwrap();
+        assert_eq!(count.get(), 2);
+    }
+
+    /// V0 to V3 after running random and timer reads seeded with `seed`.
+    fn seeded_run(seed: u64) -> [u8; 4] {
+        let clock = Rc::new(ManualClock::new());
+        let mut chip8 = Chip8::builder().clock(clock.clone()).seed(seed).build();
+        let src = "LD V0, 30\nLD DT, V0\nRND V0, 0xFF\nRND V1, 0xFF\nRND V2, 0xFF\nLD V3, DT";
+        chip8.ram.load(&asm::assemble(src).unwrap()[..]).unwrap();
+        for _ in 0..5 {
+            chip8.step().unwrap();
+            clock.advance(Duration::from_millis(20));
+        }
+        chip8.step().unwrap();
+        let mut v = [0; 4];
+        v.copy_from_slice(&chip8.cpu.state().v[..4]);
+        v
+    }
+
+    #[test]
+    fn seeded_runs_are_reproducible() {
+        let run = seeded_run(42);
+        assert_eq!(seeded_run(42), run);
+        // DT was set at 20ms and is read at 100ms, with 60Hz ticks at
+        // 33ms, 50ms, 67ms, 83ms and 100ms in between.
+        assert_eq!(run[3], 25);
+        assert_ne!(seeded_run(43)[..3], run[..3]);
+    }
+
+    #[test]
+    fn dt_follows_an_injected_clock() {
+        let clock = Rc::new(ManualClock::new());
+        let mut chip8 = Chip8::builder().clock(clock.clone()).build();
+        let src = "LD V0, 10\nLD DT, V0\nloop: LD V1, DT\nJP loop";
+        chip8.ram.load(&asm::assemble(src).unwrap()[..]).unwrap();
+        for _ in 0..3 {
+            chip8.step().unwrap();
+        }
+        assert_eq!(chip8.cpu.state().v[1], 10);
+        for n in 1..=12u8 {
+            clock.advance(Duration::from_nanos(1_000_000_000 / 60 + 1));
+            chip8.step().unwrap();
+            chip8.step().unwrap();
+            assert_eq!(chip8.cpu.state().v[1], 10u8.saturating_sub(n));
+        }
+    }
+
+    /// Store to the font of digit 0 with `Fx55` and `Fx33`.
+    const FONT_WRITES: &str = "LD V0, 0xFF\nLD I, 0\nLD [I], V0\nLD I, 1\nLD B, V0";
+
+    fn protected(write_protect: bool, src: &str) -> Chip8 {
+        let quirks = Quirks {
+            write_protect,
+            ..Quirks::default()
+        };
+        let mut chip8 = Chip8::builder().quirks(quirks).build();
+        chip8.ram.load(&asm::assemble(src).unwrap()[..]).unwrap();
+        chip8
+    }
+
+    #[test]
+    fn writes_below_0x200_are_allowed_by_default() {
+        let mut chip8 = protected(false, FONT_WRITES);
+        for _ in 0..5 {
+            chip8.step().unwrap();
+        }
+        assert_eq!(chip8.ram.buf[..4], [0xFF, 2, 5, 5]);
+    }
+
+    #[test]
+    fn write_protect_halts_fx55_below_0x200() {
+        let mut chip8 = protected(true, FONT_WRITES);
+        chip8.step().unwrap();
+        chip8.step().unwrap();
+        let halt = chip8.step().unwrap_err();
+        assert_eq!(
+            halt,
+            Halt::WriteProtected {
+                pc: 0x204,
+                addr: 0,
+                backtrace: vec![]
             }
-            (0xF, x, 0x5, 0x5) => {
-                trace!("Fx55 - LD [I], V{}", x);
-                for n in 0..x + 1 {
-                    ram.buf[self.i as usize + idx(n)] = self.v[idx(n)];
-                }
-                Next
+        );
+        assert_eq!(
+            halt.to_string(),
+            "write to protected address 0x000 at 0x204"
+        );
+        assert_eq!(chip8.ram.buf[0], 0xF0);
+    }
+
+    #[test]
+    fn write_protect_halts_fx33_below_0x200() {
+        let mut chip8 = protected(true, "LD V0, 1\nLD I, 0x1FF\nLD B, V0");
+        chip8.step().unwrap();
+        chip8.step().unwrap();
+        assert_eq!(
+            chip8.step().unwrap_err(),
+            Halt::WriteProtected {
+                pc: 0x204,
+                addr: 0x1FF,
+                backtrace: vec![]
             }
-            (0xF, x, 0x6, 0x5) => {
-                trace!("Fx65 - LD V{}, I={}", x, self.i);
-                for n in 0..x + 1 {
-                    self.v[idx(n)] = ram.buf[self.i as usize + idx(n)];
+        );
+    }
+
+    #[test]
+    fn write_protect_allows_the_program_area() {
+        let mut chip8 = protected(true, "LD V0, 1\nLD I, 0x200\nLD [I], V0");
+        for _ in 0..3 {
+            chip8.step().unwrap();
+        }
+        assert_eq!(chip8.ram.buf[0x200], 1);
+    }
+
+    #[test]
+    fn backtrace_of_nested_calls() {
+        let mut chip8 = assembled(
+            "
+            CALL outer
+            end: JP end
+            outer: CALL inner
+               RET
+            inner: LD V0, 1
+               RET
+            ",
+        );
+        assert!(chip8.cpu.backtrace().is_empty());
+        chip8.step().unwrap();
+        chip8.step().unwrap();
+        let frames = vec![
+            Frame {
+                caller: 0x204,
+                callee: 0x208,
+            },
+            Frame {
+                caller: 0x200,
+                callee: 0x204,
+            },
+        ];
+        assert_eq!(chip8.cpu.backtrace(), frames);
+        assert_eq!(frames[0].to_string(), "0x208 called from 0x204");
+        chip8.step().unwrap();
+        chip8.step().unwrap();
+        assert_eq!(chip8.cpu.backtrace(), &frames[1..]);
+        chip8.step().unwrap();
+        assert!(chip8.cpu.backtrace().is_empty());
+    }
+
+    /// `run_at` for `frames` frames.
+    fn run_frames(chip8: &mut Chip8, cpu_hz: u32, frame_rate: u32, frames: usize) {
+        let mut n = 0;
+        chip8
+            .run_at(cpu_hz, frame_rate, |_| {
+                n += 1;
+                if n == frames {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
                 }
-                Next
-            }
-            _ => {
-                panic!("N/A {:x}{:x}{:x}{:x}", o1, o2, o3, o4);
-                Next
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn run_at_paces_instructions() {
+        let mut chip8 = assembled("loop: ADD V1, 1\nJP loop");
+        run_frames(&mut chip8, 1200, 600, 60);
+        assert_eq!(chip8.cpu.cycle_count(), 120);
+        // Fractional instructions per frame add up.
+        run_frames(&mut chip8, 1000, 600, 6);
+        assert_eq!(chip8.cpu.cycle_count(), 130);
+    }
+
+    #[test]
+    fn run_at_executes_nothing_while_paused() {
+        let mut chip8 = assembled("loop: ADD V1, 1\nJP loop");
+        chip8.pause();
+        run_frames(&mut chip8, 6000, 600, 10);
+        assert_eq!(chip8.cpu.cycle_count(), 0);
+        chip8.resume();
+        run_frames(&mut chip8, 6000, 600, 1);
+        assert_eq!(chip8.cpu.cycle_count(), 10);
+    }
+
+    #[test]
+    fn run_at_returns_the_halt() {
+        let mut chip8 = assembled("LD V0, 1\nend: JP end");
+        let halt = chip8.run_at(600, 60, |_| ControlFlow::Continue(()));
+        assert_eq!(halt, Err(Halt::SpinLoop(0x202)));
+        assert_eq!(chip8.cpu.cycle_count(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "frame_rate must be at least 1")]
+    fn run_at_0_frames_per_second_panics() {
+        let mut chip8 = assembled("end: JP end");
+        let _ = chip8.run_at(600, 0, |_| ControlFlow::Break(()));
+    }
+
+    #[test]
+    fn last_byte_is_addressable_by_i() {
+        let mut chip8 =
+            assembled("LD V0, 7\nLD I, 0xFFF\nLD [I], V0\nLD V0, 0\nLD I, 0xFFF\nLD V0, [I]");
+        for _ in 0..6 {
+            chip8.tick().unwrap();
+        }
+        assert_eq!(chip8.ram.buf.len(), 4096);
+        assert_eq!(chip8.ram.buf[0xFFF], 7);
+        assert_eq!(chip8.cpu.v[0], 7);
+    }
+
+    /// Reader handing out at most 7 bytes per call.
+    struct Chunked<'a>(&'a [u8]);
+
+    impl Read for Chunked<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(7).min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn load_from_a_chunked_reader() {
+        let rom: Vec<u8> = (0..100).collect();
+        let mut ram = Ram::new();
+        assert_eq!(ram.load(Chunked(&rom)).unwrap(), 100);
+        assert_eq!(ram.buf[0x200..0x200 + 100], rom[..]);
+        assert_eq!(ram.buf[0x200 + 100], 0);
+    }
+
+    #[test]
+    fn memory_through_i_wraps_around() {
+        let mut chip8 = Chip8::builder().quirks(Quirks::xochip()).build();
+        let src = "
+            LD V0, 1\nLD V1, 2\nLD V2, 123
+            LD I, LONG 0xFFFF\nLD [I], V1
+            LD I, LONG 0xFFFE\nLD B, V2
+            LD I, LONG 0xFFFF\nLD V1, [I]
+        ";
+        chip8.ram.load(&asm::assemble(src).unwrap()[..]).unwrap();
+        for _ in 0..5 {
+            chip8.tick().unwrap();
+        }
+        assert_eq!((chip8.ram.buf[0xFFFF], chip8.ram.buf[0]), (1, 2));
+        for _ in 0..2 {
+            chip8.tick().unwrap();
+        }
+        // 123 in BCD from the last byte on.
+        assert_eq!(chip8.ram.buf[0xFFFE..], [1, 2]);
+        assert_eq!(chip8.ram.buf[0], 3);
+        for _ in 0..2 {
+            chip8.tick().unwrap();
+        }
+        assert_eq!(chip8.cpu.v[..2], [2, 3]);
+    }
+
+    #[test]
+    fn drawing_to_plane_2_leaves_plane_1() {
+        let mut chip8 = Chip8::builder().quirks(Quirks::xochip()).build();
+        let src = "
+            LD I, row
+            DRW V0, V0, 1
+            PLANE 2
+            LD V1, 2
+            DRW V1, V0, 1
+            CLS
+            end: JP end
+            row: .byte 0xF0
+        ";
+        chip8.ram.load(&asm::assemble(src).unwrap()[..]).unwrap();
+        let row = |fb: &FrameBuffer| (0..8).map(|x| fb.pixels()[x][0]).collect::<Vec<_>>();
+        chip8.tick().unwrap();
+        chip8.tick().unwrap();
+        assert_eq!(row(&chip8.fb), vec![1, 1, 1, 1, 0, 0, 0, 0]);
+        for _ in 0..3 {
+            chip8.tick().unwrap();
+        }
+        assert_eq!(row(&chip8.fb), vec![1, 1, 3, 3, 2, 2, 0, 0]);
+        // Only pixels of the selected plane collide.
+        assert_eq!(chip8.cpu.v[0xF], 0);
+        chip8.tick().unwrap();
+        assert_eq!(row(&chip8.fb), vec![1, 1, 1, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn planes_need_xochip() {
+        let mut chip8 = schip("PLANE 2");
+        assert_eq!(
+            chip8.tick(),
+            Err(Halt::UnknownOpcode {
+                pc: 0x200,
+                opcode: 0xF201,
+                trace: vec![],
+                backtrace: vec![]
+            })
+        );
+    }
+
+    #[test]
+    fn rom_of_5000_bytes_is_too_large() {
+        let mut ram = Ram::new();
+        let rom = vec![0xAA; 5000];
+        let err = ram.load(&rom[..]).unwrap_err();
+        assert_eq!(
+            err,
+            Error::RomTooLarge {
+                size: 0xE01,
+                max: 0xE00
             }
+        );
+        assert_eq!(
+            err.to_string(),
+            "ROM of at least 3585 bytes is larger than the 3584 bytes available"
+        );
+        assert_eq!(
+            ram.load_bytes(&rom),
+            Err(Error::RomTooLarge {
+                size: 5000,
+                max: 0xE00
+            })
+        );
+        // An endless stream is read no further than that.
+        assert_eq!(ram.load(std::io::repeat(0xAA)), Err(err));
+        // Nothing of it was loaded.
+        assert_eq!(ram.buf[0x200], 0);
+        assert_eq!(ram.load(&rom[..MAX_ROM_SIZE]).unwrap(), MAX_ROM_SIZE);
+    }
+
+    #[test]
+    fn ram_accessors_at_the_boundary() {
+        let mut ram = Ram::new();
+        ram.write(0xFFE, 0x12).unwrap();
+        ram.write(0xFFF, 0x34).unwrap();
+        assert_eq!(ram.size(), 0x1000);
+        assert_eq!(ram.read(0xFFF).unwrap(), 0x34);
+        assert_eq!(ram.read_word(0xFFE).unwrap(), 0x1234);
+        assert_eq!(ram.read_range(0xFFE, 2).unwrap(), &[0x12, 0x34]);
+        assert!(ram.read_range(0x1000, 0).unwrap().is_empty());
+        let out = |res: Result<_, Error>| match res {
+            Err(Error::AddressOutOfRange(addr)) => addr,
+            _ => panic!("expected AddressOutOfRange"),
         };
+        assert_eq!(out(ram.read(0x1000).map(|_| ())), 0x1000);
+        assert_eq!(out(ram.read_word(0xFFF).map(|_| ())), 0x1000);
+        assert_eq!(out(ram.read_range(0xFFE, 3).map(|_| ())), 0x1000);
+        assert_eq!(out(ram.read_range(0x2000, 1).map(|_| ())), 0x2000);
+        assert_eq!(out(ram.write(0xFFFF, 0)), 0xFFFF);
+        assert_eq!(
+            Error::AddressOutOfRange(0x1000).to_string(),
+            "address 0x1000 is out of RAM"
+        );
+    }
 
-        // Determine the next `pc`.
-        match res {
-            Next => {
-                self.pc += 2;
-            }
-            Skip => {
-                self.pc += 4;
-            }
-            Jump(loc) => {
-                self.pc = loc;
-            }
+    #[test]
+    fn bcd_wraps_at_the_end_of_ram() {
+        let mut chip8 = assembled("LD V0, 123\nLD I, 0xFFE\nLD B, V0");
+        for _ in 0..3 {
+            chip8.tick().unwrap();
         }
-        self.dump();
+        assert_eq!(chip8.ram.read_range(0xFFE, 2).unwrap(), &[1, 2]);
+        assert_eq!(chip8.ram.read(0).unwrap(), 3);
     }
 
-    fn key(&mut self, inp: &mut mpsc::Receiver<Key>) -> Option<Key> {
-        inp.try_recv().ok().or(self.key).map(|k| {
-            debug!("receiving key {:?}", k);
-            self.key = Some(k);
-            k
-        })
+    #[test]
+    fn hexdump_golden() {
+        let mut ram = Ram::new();
+        ram.load(&b"Hello, CHIP-8!\x00\xFF\x7F\n"[..]).unwrap();
+        let expected = "\
+0200: 48 65 6C 6C 6F 2C 20 43 48 49 50 2D 38 21 00 FF  |Hello, CHIP-8!..|
+0210: 7F 0A                                            |..|
+";
+        assert_eq!(ram.hexdump(0x200, 18), expected);
     }
 
-    pub fn dump(&self) {
-        trace!(
-            " v{:?} i={}({:x}) stack={:?} sp={} pc={}({:x}) dt={}",
-            self.v,
-            self.i,
-            self.i,
-            self.stack,
-            self.sp,
-            self.pc,
-            self.pc,
-            self.dt
+    #[test]
+    fn hexdump_is_clamped_to_ram() {
+        let ram = Ram::new();
+        assert_eq!(
+            ram.hexdump(0xFF8, 100),
+            "0FF8: 00 00 00 00 00 00 00 00                          |........|\n"
         );
+        assert_eq!(ram.hexdump(0x1000, 16), "");
+        assert_eq!(ram.hexdump(0xFFFF, usize::MAX), "");
     }
-}
 
-/// Chip-8 RAM.
-pub struct Ram {
-    /// Chip-8 has 0xFFFF (4096) bytes of RAM.
-    buf: [u8; 0xFFF],
-}
+    #[test]
+    fn fx29_points_at_the_chosen_font() {
+        let mut chip8 = Chip8::builder().font(Font::Vip).build();
+        chip8
+            .ram
+            .load(&asm::assemble("LD V0, 0x1\nLD F, V0").unwrap()[..])
+            .unwrap();
+        chip8.tick().unwrap();
+        chip8.tick().unwrap();
+        assert_eq!(chip8.cpu.i, 5);
+        assert_eq!(chip8.ram.read_range(5, 5).unwrap(), &Font::Vip.glyphs()[1]);
+        assert_ne!(Font::Vip.glyphs()[1], Font::Standard.glyphs()[1]);
+    }
 
-impl Ram {
-    fn new() -> Self {
-        Ram {
-            buf: [0; 0xFFF],
+    #[test]
+    fn font_at_other_base() {
+        let mut chip8 = assembled("LD V0, 0x0A\nLD F, V0");
+        let glyphs = Font::Dream6800.glyphs();
+        chip8.ram.load_fontset_from(&glyphs, 0x100).unwrap();
+        chip8.tick().unwrap();
+        chip8.tick().unwrap();
+        assert_eq!(chip8.cpu.i, 0x100 + 0xA * 5);
+        assert_eq!(chip8.ram.read_range(0x132, 5).unwrap(), &glyphs[0xA]);
+        // The font stays where it is after a reload.
+        chip8.ram.reload();
+        assert_eq!(chip8.ram.font_base(), 0x100);
+        assert_eq!(chip8.ram.read_range(0x132, 5).unwrap(), &glyphs[0xA]);
+        assert!(matches!(
+            chip8.ram.load_fontset_from(&glyphs, 0xFFF),
+            Err(Error::AddressOutOfRange(0x104E))
+        ));
+    }
+
+    #[test]
+    fn font_must_not_overlap() {
+        let mut ram = Ram::new();
+        let glyphs = Font::Standard.glyphs();
+        // The big font is at 0x50..0xF0.
+        for base in [0x00, 0xF0, 0x1B0] {
+            assert_eq!(ram.load_fontset_from(&glyphs, base), Ok(()));
+        }
+        for base in [0x01, 0x60, 0xEF, 0x1B1, 0x200] {
+            assert_eq!(
+                ram.load_fontset_from(&glyphs, base),
+                Err(Error::FontOverlaps(base))
+            );
         }
+        assert_eq!(ram.font_base(), 0x1B0);
     }
 
-    /// Load Chip8 ROM into memory.
-    pub fn load<S: Read>(&mut self, mut stream: S) -> Result<(), Error> {
-        self.load_fontset();
-        loop {
-            if stream.read(&mut self.buf[0x200..])? == 0 {
-                break;
+    #[test]
+    fn ram_snapshot_restores_every_byte() {
+        let mut ram = Ram::new();
+        ram.load(&[0x12, 0x00][..]).unwrap();
+        let snapshot = ram.snapshot();
+        let before = ram.buf.clone();
+        assert_eq!(snapshot.bytes(), &before[..]);
+
+        ram.write(0, 0xAA).unwrap();
+        ram.write(0x200, 0xBB).unwrap();
+        ram.write(0xFFF, 0xCC).unwrap();
+        assert_ne!(ram.snapshot(), snapshot);
+
+        ram.restore(&snapshot);
+        assert_eq!(ram.buf, before);
+        assert_eq!(ram.snapshot(), snapshot);
+    }
+
+    #[test]
+    fn ram_snapshots_hash_by_contents() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+        let hash = |snapshot: &RamSnapshot| {
+            let mut hasher = DefaultHasher::new();
+            snapshot.hash(&mut hasher);
+            hasher.finish()
+        };
+        let mut ram = Ram::new();
+        let empty = ram.snapshot();
+        ram.write(0x300, 1).unwrap();
+        assert_ne!(hash(&ram.snapshot()), hash(&empty));
+        ram.write(0x300, 0).unwrap();
+        assert_eq!(hash(&ram.snapshot()), hash(&empty));
+    }
+
+    #[test]
+    fn sprite_at_the_end_of_ram_wraps() {
+        let mut chip8 = Chip8::builder().build();
+        chip8
+            .ram
+            .load(&asm::assemble("LD I, 0xFFE\nDRW V0, V0, 5").unwrap()[..])
+            .unwrap();
+        chip8.ram.write(0xFFE, 0x80).unwrap();
+        chip8.ram.write(0xFFF, 0x40).unwrap();
+        chip8.tick().unwrap();
+        chip8.tick().unwrap();
+        // The last 3 rows are the top of the font's 0 at address 0.
+        let mut expected = vec![(0, 0), (0, 2), (0, 3), (0, 4), (1, 1), (1, 2)];
+        expected.extend_from_slice(&[(2, 2), (3, 2), (3, 3), (3, 4)]);
+        assert_eq!(lit(&chip8.fb), expected);
+    }
+
+    #[test]
+    fn rom_info_of_the_loaded_rom() {
+        let mut chip8 = Chip8::builder().build();
+        assert_eq!(chip8.rom_info(), None);
+        chip8
+            .load_rom_bytes(include_bytes!("../../roms/MAZE"))
+            .unwrap();
+        let info = chip8.rom_info().unwrap();
+        assert_eq!(
+            info,
+            RomInfo {
+                len: 34,
+                hash: "b9272ae1acdaaa79ab649f6b48b72088ca2b1d74".to_string(),
             }
+        );
+        assert_eq!(
+            info.to_string(),
+            "34 bytes, SHA-1 b9272ae1acdaaa79ab649f6b48b72088ca2b1d74"
+        );
+        // Changes made by the program itself don't count.
+        chip8.ram.write(0x200, 0).unwrap();
+        assert_eq!(chip8.rom_info(), Some(info));
+    }
+
+    #[test]
+    fn load_at_an_offset() {
+        let mut ram = Ram::new();
+        ram.load(&[0x11, 0x22][..]).unwrap();
+        assert_eq!(ram.load_at(&[0xAA, 0xBB][..], 0x800).unwrap(), 2);
+        assert_eq!(ram.read_range(0x800, 2).unwrap(), &[0xAA, 0xBB]);
+        // The program at the start address is kept.
+        assert_eq!(ram.read_range(0x200, 2).unwrap(), &[0x11, 0x22]);
+        assert_eq!(ram.start_addr(), 0x200);
+        // A reload puts back the last ROM where it was loaded.
+        ram.write(0x800, 0).unwrap();
+        ram.reload();
+        assert_eq!(ram.read_range(0x800, 2).unwrap(), &[0xAA, 0xBB]);
+        assert_eq!(ram.read_range(0x200, 2).unwrap(), &[0, 0]);
+    }
+
+    #[test]
+    fn load_at_must_fit_in_ram() {
+        let mut ram = Ram::new();
+        assert_eq!(ram.load_at(&[1; 16][..], 0xFF0).unwrap(), 16);
+        assert!(matches!(
+            ram.load_at(&[1; 17][..], 0xFF0),
+            Err(Error::RomTooLarge { size: 17, max: 16 })
+        ));
+        assert!(matches!(
+            ram.load_bytes_at(&[1], 0x1000),
+            Err(Error::RomTooLarge { size: 1, max: 0 })
+        ));
+    }
+
+    #[test]
+    fn pause_freezes_the_timers() {
+        let clock = Rc::new(ManualClock::new());
+        let mut chip8 = Chip8::builder().clock(clock.clone()).build();
+        chip8
+            .ram
+            .load(&asm::assemble("LD V0, 60\nLD DT, V0\nLD ST, V0").unwrap()[..])
+            .unwrap();
+        for _ in 0..3 {
+            chip8.tick().unwrap();
         }
+        chip8.pause();
+        clock.advance(Duration::from_secs(1));
+        assert_eq!((chip8.cpu.dt.get(), chip8.cpu.st.get()), (60, 60));
+        chip8.resume();
+        clock.advance(Duration::from_millis(500));
+        assert_eq!((chip8.cpu.dt.get(), chip8.cpu.st.get()), (30, 30));
+    }
 
-        Ok(())
+    #[test]
+    fn set_paused_pauses_and_resumes() {
+        let mut chip8 = Chip8::builder().build();
+        chip8.set_paused(true);
+        assert!(chip8.is_paused());
+        chip8.set_paused(false);
+        assert!(!chip8.is_paused());
     }
 
-    fn load_fontset(&mut self) {
-        let fontset = vec![
-            0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-            0x20, 0x60, 0x20, 0x20, 0x70, // 1
-            0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-            0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-            0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-            0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-            0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-            0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-            0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-            0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-            0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-            0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-            0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-            0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-            0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-            0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+    #[test]
+    fn pausing_twice_resumes_once() {
+        let clock = Rc::new(ManualClock::new());
+        let mut timer = DelayTimer::with_clock(clock.clone());
+        timer.set(60);
+        timer.pause();
+        clock.advance(Duration::from_millis(500));
+        timer.pause();
+        clock.advance(Duration::from_millis(500));
+        timer.resume();
+        timer.resume();
+        assert_eq!(timer.get(), 60);
+    }
+
+    #[test]
+    fn alu_wraps_and_flags() {
+        // (src, V0, VF) after running `src` with `V1` = 0xFF and `V2` = 1.
+        let cases = [
+            ("ADD V1, 1\nLD V0, V1", 0x00, 0),
+            ("LD VF, 7\nADD V1, 2\nLD V0, V1", 0x01, 7),
+            ("LD V0, V1\nADD V0, V2", 0x00, 1),
+            ("LD V0, V2\nADD V0, V2", 0x02, 0),
+            ("LD V0, V2\nSUB V0, V1", 0x02, 0),
+            ("LD V0, V1\nSUB V0, V2", 0xFE, 1),
+            ("LD V0, V2\nSUB V0, V2", 0x00, 1),
+            ("LD V0, V1\nSUBN V0, V2", 0x02, 0),
+            ("LD V0, V2\nSUBN V0, V1", 0xFE, 1),
+            ("LD V0, V1\nSHR V0", 0x7F, 1),
+            ("LD V0, 0x80\nSHR V0", 0x40, 0),
+            ("LD V0, V1\nSHL V0", 0xFE, 1),
+            ("LD V0, 0x40\nSHL V0", 0x80, 0),
         ];
-        &self.buf[..fontset.len()].copy_from_slice(&fontset);
+        for &(src, v0, vf) in cases.iter() {
+            let src = format!("LD V1, 0xFF\nLD V2, 1\n{}", src);
+            let cpu = run_with(Quirks::schip(), &src, src.lines().count());
+            assert_eq!((cpu.v[0], cpu.v[0xF]), (v0, vf), "{}", src);
+        }
+    }
+
+    #[test]
+    fn add_i_past_0xfff() {
+        let src = "LD I, 0xFFF\nLD V0, 2\nLD VF, 7\nADD I, V0";
+        let cpu = run_with(Quirks::default(), src, 4);
+        assert_eq!((cpu.i, cpu.v[0xF]), (0x1001, 7));
+
+        let src = "LD I, LONG 0xFFFF\nLD V0, 2\nADD I, V0";
+        assert_eq!(run_with(Quirks::xochip(), src, 3).i, 0x0001);
+    }
+
+    #[test]
+    fn quirk_i_overflow_sets_vf() {
+        let quirks = Quirks {
+            i_overflow_sets_vf: true,
+            ..Quirks::default()
+        };
+        let src = "LD I, 0xFFE\nLD V0, 1\nLD VF, 7\nADD I, V0";
+        let cpu = run_with(quirks, src, 4);
+        assert_eq!((cpu.i, cpu.v[0xF]), (0xFFF, 0));
+        let cpu = run_with(quirks, &format!("{}\nADD I, V0", src), 5);
+        assert_eq!((cpu.i, cpu.v[0xF]), (0x1000, 1));
+    }
+
+    #[test]
+    fn ticks_of_elapsed_time() {
+        assert_eq!(ticks(Duration::ZERO), 0);
+        assert_eq!(ticks(Duration::from_nanos(16_666_666)), 0);
+        assert_eq!(ticks(Duration::from_nanos(16_666_667)), 1);
+        assert_eq!(ticks(Duration::from_secs(1)), 60);
+        assert_eq!(ticks(Duration::from_secs(3600)), 216_000);
+    }
+
+    #[test]
+    fn timer_counts_on_the_global_60hz_ticks() {
+        let clock = Rc::new(ManualClock::new());
+        let mut timer = DelayTimer::with_clock(clock.clone());
+        clock.advance(Duration::from_millis(10));
+        timer.set(2);
+        // The first tick is at 16.7ms, not 16.7ms after it was set.
+        clock.advance(Duration::from_millis(7));
+        assert_eq!(timer.get(), 1);
+        clock.advance(Duration::from_millis(16));
+        assert_eq!(timer.get(), 1);
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(timer.get(), 0);
+        // Never below 0, however long it was.
+        clock.advance(Duration::from_secs(100));
+        assert_eq!(timer.get(), 0);
+    }
+
+    #[test]
+    fn time_until_next_tick() {
+        let clock = Rc::new(ManualClock::new());
+        let chip8 = Chip8::builder().clock(clock.clone()).build();
+        assert_eq!(
+            chip8.time_until_next_tick(),
+            Duration::from_nanos(16_666_667)
+        );
+        clock.advance(Duration::from_millis(10));
+        assert_eq!(
+            chip8.time_until_next_tick(),
+            Duration::from_nanos(6_666_667)
+        );
+        clock.advance(Duration::from_nanos(6_666_667));
+        assert_eq!(
+            chip8.time_until_next_tick(),
+            Duration::from_nanos(16_666_667)
+        );
+    }
+
+    #[test]
+    fn cls_and_collision_through_the_cpu() {
+        let mut chip8 = Chip8::builder().build();
+        let src = "LD F, V0\nDRW V0, V0, 5\nCLS\nDRW V0, V0, 5\nDRW V0, V0, 1";
+        chip8.ram.load(&asm::assemble(src).unwrap()[..]).unwrap();
+        chip8.tick().unwrap();
+        chip8.tick().unwrap();
+        assert_eq!(lit(&chip8.fb).len(), 14);
+        chip8.tick().unwrap();
+        assert!(lit(&chip8.fb).is_empty());
+        chip8.tick().unwrap();
+        assert_eq!(chip8.cpu.v[0xF], 0);
+        // Erasing the top row of the 0 collides.
+        chip8.tick().unwrap();
+        assert_eq!(chip8.cpu.v[0xF], 1);
+        assert_eq!(lit(&chip8.fb).len(), 10);
+    }
+
+    #[test]
+    fn clipped_pixels_dont_collide() {
+        let src = "
+            LD I, dot
+            DRW V0, V0, 1
+            LD V1, 63
+            LD I, pair
+            DRW V1, V0, 1
+            end: JP end
+            dot: .byte 0x80
+            pair: .byte 0xC0
+        ";
+        let mut chip8 = assembled(src);
+        for _ in 0..5 {
+            chip8.tick().unwrap();
+        }
+        // The second pixel would wrap onto (0, 0) if it wasn't clipped.
+        assert_eq!(chip8.cpu.v[0xF], 0);
+        assert_eq!(lit(&chip8.fb), vec![(0, 0), (63, 0)]);
+    }
+
+    #[test]
+    fn reset_vf_keeps_the_logic_results() {
+        let reset = Quirks {
+            reset_vf: true,
+            ..Quirks::default()
+        };
+        for &(op, v0) in [("OR", 0x3F), ("AND", 0x0C), ("XOR", 0x33)].iter() {
+            let src = format!("LD V0, 0x0F\nLD V1, 0x3C\nLD VF, 5\n{} V0, V1", op);
+            let cpu = run_with(reset, &src, 4);
+            assert_eq!((cpu.v[0], cpu.v[0xF]), (v0, 0), "{}", op);
+            let cpu = run_with(Quirks::default(), &src, 4);
+            assert_eq!((cpu.v[0], cpu.v[0xF]), (v0, 5), "{}", op);
+        }
+    }
+
+    fn broken<T>() -> Result<T, Error> {
+        Err(Error::Display("broken pipe".to_string()))
+    }
+
+    /// `Display` which fails everything, like a closed terminal.
+    struct Broken;
+
+    impl Display for Broken {
+        fn present(&mut self, _: &FrameBuffer) -> Result<(), Error> {
+            broken()
+        }
+        fn clear(&mut self) -> Result<(), Error> {
+            broken()
+        }
+    }
+
+    #[test]
+    fn display_errors_halt() {
+        for src in ["CLS", "DRW V0, V0, 1", "SCR", "HIGH"] {
+            let mut chip8 = Chip8::builder()
+                .quirks(Quirks::schip())
+                .display(Box::new(Broken))
+                .build();
+            chip8.ram.load(&asm::assemble(src).unwrap()[..]).unwrap();
+            let halt = chip8.tick().unwrap_err();
+            assert_eq!(
+                halt,
+                Halt::DisplayFailed {
+                    pc: 0x200,
+                    error: Error::Display("broken pipe".to_string()),
+                    backtrace: vec![],
+                },
+                "{}",
+                src
+            );
+            assert_eq!(halt.to_string(), "display failed: broken pipe at 0x200");
+            // The instruction is done, and the screen is presented again
+            // after the next one.
+            assert_eq!(chip8.cpu.pc, 0x202);
+            assert!(!chip8.fb.dirty().is_empty());
+        }
+    }
+
+    #[test]
+    fn screen_is_presented_when_it_changes() {
+        let dsp = Rc::new(RefCell::new(RecordingDisplay::new()));
+        let mut chip8 = Chip8::builder()
+            .quirks(Quirks::schip())
+            .display(Box::new(Rc::clone(&dsp)))
+            .build();
+        let src = "CLS\nLD V0, 1\nDRW V0, V0, 1\nDRW V0, V0, 1\nHIGH\nSCD 2";
+        chip8.ram.load(&asm::assemble(src).unwrap()[..]).unwrap();
+        for _ in 0..6 {
+            chip8.tick().unwrap();
+        }
+        let blank = vec![0; WIDTH * HEIGHT];
+        // Top row of the 0 in the font at (1, 1).
+        let mut drawn = blank.clone();
+        drawn[WIDTH + 1..WIDTH + 5].copy_from_slice(&[1; 4]);
+        let hires = vec![0; HIRES_WIDTH * HIRES_HEIGHT];
+        // LD V0, 1 leaves the screen as it is, so isn't presented.
+        assert_eq!(
+            dsp.borrow().calls,
+            vec![
+                DisplayCall::Present(blank.clone()),
+                DisplayCall::Present(drawn),
+                DisplayCall::Present(blank),
+                DisplayCall::Clear,
+                DisplayCall::Present(hires.clone()),
+                DisplayCall::Present(hires),
+            ]
+        );
+        // The second draw erased the first, which is a collision.
+        assert_eq!(chip8.cpu.v[0xF], 1);
+        assert!(chip8.fb.dirty().is_empty());
+    }
+
+    #[test]
+    fn dirty_pixels_of_draws() {
+        let mut fb = FrameBuffer::new();
+        // A new buffer is all dirty, so the first frame paints everything.
+        assert_eq!(fb.dirty().len(), WIDTH * HEIGHT);
+        fb.mark_clean();
+        fb.draw(1, 2, &[0b1100_0000]);
+        assert_eq!(fb.dirty(), vec![(1, 2, 1), (2, 2, 1)]);
+        fb.mark_clean();
+        // Erasing an overlapping sprite changes both the erased and the
+        // newly lit pixel.
+        fb.draw(2, 2, &[0b1100_0000]);
+        assert_eq!(fb.dirty(), vec![(2, 2, 0), (3, 2, 1)]);
+        fb.mark_clean();
+        assert_eq!(fb.dirty(), vec![]);
+    }
+
+    #[test]
+    fn clear_marks_everything_dirty() {
+        let mut fb = FrameBuffer::new();
+        fb.mark_clean();
+        fb.clear();
+        let dirty = fb.dirty();
+        assert_eq!(dirty.len(), WIDTH * HEIGHT);
+        assert!(dirty.iter().all(|&(_, _, p)| p == 0));
+        fb.mark_clean();
+        fb.set_resolution(Resolution::High);
+        assert_eq!(fb.dirty().len(), HIRES_WIDTH * HIRES_HEIGHT);
+    }
+
+    #[test]
+    fn pbm_of_low_resolution() {
+        let mut fb = FrameBuffer::new();
+        fb.draw(0, 0, &[0xF0]);
+        fb.draw(9, 1, &[0x80]);
+        let mut expected = b"P4\n64 32\n".to_vec();
+        let mut bits = vec![0; 8 * 32];
+        bits[0] = 0xF0;
+        bits[8 + 1] = 0x40;
+        expected.extend(bits);
+        assert_eq!(fb.to_pbm(), expected);
+    }
+
+    #[test]
+    fn pbm_of_high_resolution() {
+        let mut fb = FrameBuffer::new();
+        fb.set_resolution(Resolution::High);
+        fb.draw(120, 63, &[0x81]);
+        let mut expected = b"P4\n128 64\n".to_vec();
+        let mut bits = vec![0; 16 * 64];
+        bits[16 * 63 + 15] = 0x81;
+        expected.extend(bits);
+        assert_eq!(fb.to_pbm(), expected);
+    }
+
+    #[test]
+    fn screenshot_of_the_screen() {
+        let mut chip8 = Chip8::builder().build();
+        chip8
+            .ram
+            .load(&asm::assemble("LD F, V0\nDRW V0, V0, 5").unwrap()[..])
+            .unwrap();
+        chip8.tick().unwrap();
+        chip8.tick().unwrap();
+        let pbm = chip8.screenshot();
+        assert_eq!(pbm, chip8.fb.to_pbm());
+        // Rows of the 0 in the font.
+        let rows: Vec<u8> = (0..5).map(|y| pbm[9 + y * 8]).collect();
+        assert_eq!(rows, vec![0xF0, 0x90, 0x90, 0x90, 0xF0]);
     }
 }
@@ -0,0 +1,214 @@
+use crate::{Quirks, RomInfo};
+
+/// Platform a ROM was written for, named after the platforms of the chip-8
+/// database (https://github.com/chip-8/chip-8-database).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Platform {
+    /// `originalChip8`, the COSMAC VIP interpreter.
+    OriginalChip8,
+    /// `chip48`, the HP48 interpreter most games of the early 90s were
+    /// written for.
+    Chip48,
+    /// `superchip`, SUPER-CHIP 1.1.
+    Superchip,
+    /// `xochip`, Octo's XO-CHIP.
+    Xochip,
+}
+
+impl Platform {
+    /// Quirks of the platform as listed by the database.
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Platform::OriginalChip8 => Quirks::chip8(),
+            Platform::Chip48 => Quirks::chip48(),
+            Platform::Superchip => Quirks::schip(),
+            Platform::Xochip => Quirks::xochip(),
+        }
+    }
+}
+
+/// ROM of the database.
+struct Rom {
+    sha1: &'static str,
+    size: usize,
+    platform: Platform,
+}
+
+use self::Platform::*;
+
+/// Known ROMs by SHA-1 and size with the platform they were written for.
+/// Seeded with the ROMs shipped in `roms/`.
+#[rustfmt::skip]
+const ROMS: &[Rom] = &[
+    // 15PUZZLE
+    Rom { sha1: "ea9af3c09b0d9e265fcd92bcc5d51a2939fdf27a", size: 384, platform: OriginalChip8 },
+    // BLINKY
+    Rom { sha1: "d40abc54374e4343639f993e897e00904ddf85d9", size: 2356, platform: Chip48 },
+    // BLITZ
+    Rom { sha1: "6f6509f38220e057a7e32ebb22dd353c1078e3e7", size: 391, platform: OriginalChip8 },
+    // BRIX
+    Rom { sha1: "f13766c14aeb02ad8d4d103cb5eadd282d20cddc", size: 280, platform: Chip48 },
+    // CONNECT4
+    Rom { sha1: "2d10c07b532f4fa7c07a07324ba26ca39fe484fd", size: 194, platform: OriginalChip8 },
+    // GUESS
+    Rom { sha1: "5260f8931e0e9f41e555b382a14a88368e3ed886", size: 148, platform: OriginalChip8 },
+    // HIDDEN
+    Rom { sha1: "050f07a54371da79f924dd0227b89d07b4f2aed0", size: 850, platform: OriginalChip8 },
+    // INVADERS
+    Rom { sha1: "f100197f0f2f05b4f3c8c31ab9c2c3930d3e9571", size: 1283, platform: Chip48 },
+    // KALEID
+    Rom { sha1: "d6fa9dc9005dc0496f39ba52fef56f9fd0a5a158", size: 120, platform: OriginalChip8 },
+    // MAZE
+    Rom { sha1: "b9272ae1acdaaa79ab649f6b48b72088ca2b1d74", size: 34, platform: OriginalChip8 },
+    // MERLIN
+    Rom { sha1: "d979858bb9ffd07b48f52f92a8bcac0199f3623e", size: 345, platform: OriginalChip8 },
+    // MISSILE
+    Rom { sha1: "0d0cc129dad3c45ba672f85fec71a668232212cc", size: 180, platform: OriginalChip8 },
+    // PONG
+    Rom { sha1: "b232ef880bd6060fb45fa6effed7edf0ae95670e", size: 246, platform: Chip48 },
+    // PONG2
+    Rom { sha1: "a60611339661e3ab2d8af024ad1da5880a6f8665", size: 264, platform: Chip48 },
+    // PUZZLE
+    Rom { sha1: "1293db0ccccbe7dd3fc5a09a2abc5d7b175e18e0", size: 184, platform: OriginalChip8 },
+    // SYZYGY
+    Rom { sha1: "1bdb4ddaa7049266fa3226851f28855a365cfd12", size: 946, platform: Chip48 },
+    // TANK
+    Rom { sha1: "18b9d15f4c159e1f0ed58c2d8ec1d89325d3a3b6", size: 560, platform: OriginalChip8 },
+    // TETRIS
+    Rom { sha1: "5f518084744bf3cb8733f6e5454dfd1634320563", size: 494, platform: Chip48 },
+    // TICTAC
+    Rom { sha1: "429d455a4bc53167942bf6fd934d72b0f648dce3", size: 486, platform: OriginalChip8 },
+    // UFO
+    Rom { sha1: "bdb92475acfe11bc7814a2f5eade13fcd09b756a", size: 224, platform: OriginalChip8 },
+    // VBRIX
+    Rom { sha1: "da710f631f8e35534d0b9170bcf892a60f49c43d", size: 507, platform: OriginalChip8 },
+    // VERS
+    Rom { sha1: "ade839585ddeb0e3633177df03c1d91589e629eb", size: 230, platform: Chip48 },
+    // WIPEOFF
+    Rom { sha1: "d666688a8fce468a7d88b536bc1ef5f35ba12031", size: 206, platform: OriginalChip8 },
+];
+
+/// Quirks recommended for `rom`, `None` if it isn't in the database.
+pub fn lookup(rom: &[u8]) -> Option<Quirks> {
+    lookup_info(&RomInfo::of(rom))
+}
+
+/// Quirks recommended for the ROM identified by `info`.
+pub fn lookup_info(info: &RomInfo) -> Option<Quirks> {
+    ROMS.iter()
+        .find(|rom| rom.size == info.len && rom.sha1 == info.hash)
+        .map(|rom| rom.platform.quirks())
+}
+
+/// SHA-1 of `data` in lowercase hex, the fingerprint used by `ROMS`.
+pub fn sha1_hex(data: &[u8]) -> String {
+    sha1(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// SHA-1 digest of `data`.
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [
+        0x6745_2301,
+        0xEFCD_AB89,
+        0x98BA_DCFE,
+        0x1032_5476,
+        0xC3D2_E1F0,
+    ];
+
+    // Pad with 0x80, zeros and the length in bits to a multiple of 64 bytes.
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in msg.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+            let t = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*wi);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+        for (x, y) in h.iter_mut().zip([a, b, c, d, e].iter()) {
+            *x = x.wrapping_add(*y);
+        }
+    }
+
+    let mut out = [0; 20];
+    for (i, x) in h.iter().enumerate() {
+        out[i * 4..i * 4 + 4].copy_from_slice(&x.to_be_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha1_vectors() {
+        assert_eq!(sha1_hex(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        // 56 bytes, which needs a second block for the length.
+        assert_eq!(
+            sha1_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+        assert_eq!(
+            sha1_hex(&[b'a'; 1000]),
+            "291e9a6c66994949b57ba5e650361e98fc36b1ba"
+        );
+    }
+
+    #[test]
+    fn known_roms() {
+        assert_eq!(
+            lookup(include_bytes!("../../roms/MAZE")),
+            Some(Quirks::chip8())
+        );
+        assert_eq!(
+            lookup(include_bytes!("../../roms/PONG")),
+            Some(Quirks::chip48())
+        );
+    }
+
+    #[test]
+    fn unknown_roms() {
+        let mut rom = include_bytes!("../../roms/MAZE").to_vec();
+        rom[0] ^= 1;
+        assert_eq!(lookup(&rom), None);
+        assert_eq!(lookup(&[]), None);
+    }
+
+    #[test]
+    fn all_roms_are_known() {
+        for entry in std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/../roms")).unwrap() {
+            let path = entry.unwrap().path();
+            let rom = std::fs::read(&path).unwrap();
+            assert!(lookup(&rom).is_some(), "{}", path.display());
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Gamepad input through gilrs, feeding presses into the keyboard's channel.
+
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use gilrs::{Button, EventType, Gilrs};
+use log::*;
+
+/// How often connected gamepads are polled for events.
+const POLL_INTERVAL: Duration = Duration::from_millis(4);
+
+/// Chip8 keys of gamepad buttons by default. The d-pad is on 2/4/6/8 which
+/// most games move with, and the face buttons on the keys around it.
+const DEFAULT_BUTTONS: [(Button, u8); 12] = [
+    (Button::DPadUp, 0x2),
+    (Button::DPadLeft, 0x4),
+    (Button::DPadRight, 0x6),
+    (Button::DPadDown, 0x8),
+    (Button::South, 0x5),
+    (Button::East, 0x0),
+    (Button::West, 0xA),
+    (Button::North, 0xB),
+    (Button::LeftTrigger, 0xC),
+    (Button::RightTrigger, 0xD),
+    (Button::Select, 0xE),
+    (Button::Start, 0xF),
+];
+
+/// Button of the gilrs name `name`, e.g. `DPadUp` or `South`.
+pub fn button(name: &str) -> Option<Button> {
+    let button = match name {
+        "South" => Button::South,
+        "East" => Button::East,
+        "North" => Button::North,
+        "West" => Button::West,
+        "C" => Button::C,
+        "Z" => Button::Z,
+        "LeftTrigger" => Button::LeftTrigger,
+        "LeftTrigger2" => Button::LeftTrigger2,
+        "RightTrigger" => Button::RightTrigger,
+        "RightTrigger2" => Button::RightTrigger2,
+        "Select" => Button::Select,
+        "Start" => Button::Start,
+        "Mode" => Button::Mode,
+        "LeftThumb" => Button::LeftThumb,
+        "RightThumb" => Button::RightThumb,
+        "DPadUp" => Button::DPadUp,
+        "DPadDown" => Button::DPadDown,
+        "DPadLeft" => Button::DPadLeft,
+        "DPadRight" => Button::DPadRight,
+        _ => return None,
+    };
+    Some(button)
+}
+
+/// Default button mapping with the buttons named in `overrides` remapped.
+fn mapping(overrides: &HashMap<String, core::Key>) -> HashMap<Button, core::Key> {
+    let mut map: HashMap<_, _> = DEFAULT_BUTTONS
+        .iter()
+        .map(|&(b, k)| (b, core::Key(k)))
+        .collect();
+    for (name, key) in overrides {
+        if let Some(b) = button(name) {
+            map.insert(b, *key);
+        }
+    }
+    map
+}
+
+/// Send the Chip8 keys of pressed gamepad buttons to `keypad` from a
+/// thread of its own. Without a gamepad, only the keyboard is used.
+pub fn spawn(keypad: mpsc::Sender<core::Key>, overrides: &HashMap<String, core::Key>) {
+    let map = mapping(overrides);
+    thread::spawn(move || {
+        let mut gilrs = match Gilrs::new() {
+            Ok(gilrs) => gilrs,
+            Err(e) => {
+                warn!("Gamepads unavailable, using the keyboard only: {}", e);
+                return;
+            }
+        };
+        if gilrs.gamepads().next().is_none() {
+            info!("No gamepad connected, using the keyboard until one is");
+        }
+        loop {
+            while let Some(ev) = gilrs.next_event() {
+                match ev.event {
+                    EventType::ButtonPressed(b, _) => {
+                        if let Some(&k) = map.get(&b) {
+                            debug!("sending key {:?} of {:?}", k, b);
+                            if keypad.send(k).is_err() {
+                                // Emulator is gone.
+                                return;
+                            }
+                        }
+                    }
+                    EventType::Connected => info!("Gamepad {:?} connected", ev.id),
+                    EventType::Disconnected => info!("Gamepad {:?} disconnected", ev.id),
+                    _ => {}
+                }
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    });
+}
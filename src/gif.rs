@@ -0,0 +1,147 @@
+//! Minimal animated GIF writer for recording the screen.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// Bits of the initial LZW codes minus one, enough for 4 colors.
+const MIN_CODE_SIZE: u8 = 2;
+
+/// LZW codes are at most 12 bits.
+const MAX_CODE: u16 = 4095;
+
+/// Writes frames of `width` x `height` indices into a 4 color palette as a
+/// GIF looping forever.
+pub struct Encoder<W: Write> {
+    w: W,
+    width: u16,
+    height: u16,
+    /// How long each frame is shown in 1/100 seconds.
+    delay: u16,
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(
+        mut w: W,
+        width: u16,
+        height: u16,
+        palette: &[[u8; 3]; 4],
+        delay: u16,
+    ) -> io::Result<Self> {
+        w.write_all(b"GIF89a")?;
+        w.write_all(&width.to_le_bytes())?;
+        w.write_all(&height.to_le_bytes())?;
+        // Global color table of 2^(1+1) colors.
+        w.write_all(&[0xF1, 0, 0])?;
+        for rgb in palette {
+            w.write_all(rgb)?;
+        }
+        // Loop forever.
+        w.write_all(&[0x21, 0xFF, 0x0B])?;
+        w.write_all(b"NETSCAPE2.0")?;
+        w.write_all(&[0x03, 0x01, 0x00, 0x00, 0x00])?;
+        Ok(Encoder {
+            w,
+            width,
+            height,
+            delay,
+        })
+    }
+
+    /// Append a frame of `width * height` palette indices, row by row.
+    pub fn frame(&mut self, pixels: &[u8]) -> io::Result<()> {
+        let w = &mut self.w;
+        // Graphic control extension with the delay.
+        w.write_all(&[0x21, 0xF9, 0x04, 0x00])?;
+        w.write_all(&self.delay.to_le_bytes())?;
+        w.write_all(&[0x00, 0x00])?;
+        // Image descriptor covering the whole screen.
+        w.write_all(&[0x2C, 0, 0, 0, 0])?;
+        w.write_all(&self.width.to_le_bytes())?;
+        w.write_all(&self.height.to_le_bytes())?;
+        w.write_all(&[0x00, MIN_CODE_SIZE])?;
+        for block in lzw(pixels).chunks(255) {
+            w.write_all(&[block.len() as u8])?;
+            w.write_all(block)?;
+        }
+        w.write_all(&[0x00])
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.w.write_all(&[0x3B])?;
+        self.w.flush()
+    }
+}
+
+/// Packs codes of varying width least significant bit first.
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u32,
+    bits: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u8) {
+        self.acc |= (code as u32) << self.bits;
+        self.bits += size;
+        while self.bits >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.out.push(self.acc as u8);
+        }
+        self.out
+    }
+}
+
+/// LZW compress `pixels` the way GIF decoders expect.
+fn lzw(pixels: &[u8]) -> Vec<u8> {
+    let clear = 1u16 << MIN_CODE_SIZE;
+    let end = clear + 1;
+    let mut w = BitWriter {
+        out: Vec::new(),
+        acc: 0,
+        bits: 0,
+    };
+    let mut dict: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next = end + 1;
+    let mut size = MIN_CODE_SIZE + 1;
+
+    w.write(clear, size);
+    let mut pixels = pixels.iter();
+    let mut prefix = match pixels.next() {
+        Some(&p) => p as u16,
+        None => {
+            w.write(end, size);
+            return w.finish();
+        }
+    };
+    for &p in pixels {
+        if let Some(&code) = dict.get(&(prefix, p)) {
+            prefix = code;
+            continue;
+        }
+        w.write(prefix, size);
+        if next < MAX_CODE {
+            dict.insert((prefix, p), next);
+            next += 1;
+            // Decoders widen codes once the table outgrows them.
+            if next > 1 << size {
+                size += 1;
+            }
+        } else {
+            w.write(clear, size);
+            dict.clear();
+            next = end + 1;
+            size = MIN_CODE_SIZE + 1;
+        }
+        prefix = p as u16;
+    }
+    w.write(prefix, size);
+    w.write(end, size);
+    w.finish()
+}
@@ -1,67 +1,263 @@
-use std::convert::From;
+use std::collections::HashMap;
 use std::default::Default;
-use std::path::PathBuf;
+use std::io::Write;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::thread::sleep;
-use std::time::{Duration, Instant};
+use std::sync::MutexGuard;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use core::{Chip8, Display};
+use core::{
+    Chip8, Display, Font, FrameBuffer, Halt, Quirks, DEFAULT_TRACE_DEPTH, HIRES_HEIGHT, HIRES_WIDTH,
+};
 use log::*;
 use rustbox::{
-    Color::{self, Black, White},
+    Color::{self, Black, Blue, Cyan, Green, Magenta, Red, White, Yellow},
     Key, RustBox, RB_BOLD,
 };
 use structopt::StructOpt;
 
-static PIXEL: char = ' ';
+#[cfg(feature = "gilrs")]
+mod gamepad;
+mod gif;
 
-const WIDTH: usize = 64;
+static PIXEL: char = ' ';
 
-const HEIGHT: usize = 32;
+/// Fills the upper half of a cell with the foreground color, so that a cell
+/// shows two pixels on top of each other.
+static UPPER_HALF: char = '▀';
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "chip8", about = "chip8 program options.")]
 struct Args {
     rom: PathBuf,
-    #[structopt(short = "f", long = "fps", default_value = "300")]
+    /// Frames drawn per second, at least 1.
+    #[structopt(short = "f", long = "fps", default_value = "60", parse(try_from_str = parse_fps))]
     fps: i32,
+    /// Terminal cells per pixel horizontally, and half cells vertically.
+    #[structopt(short = "s", long = "scale", default_value = "1")]
+    scale: usize,
+    /// CPU speed in instructions per second, at least 1.
+    #[structopt(short = "c", long = "clock", default_value = "500", parse(try_from_str = parse_clock))]
+    clock: u32,
+    /// How many times faster Tab makes the CPU run. Timers keep to 60Hz.
+    #[structopt(long = "turbo-multiplier", default_value = "4")]
+    turbo_multiplier: u32,
+    /// Follow the quirks of the original COSMAC VIP interpreter.
+    /// Without any of these flags, quirks of known ROMs are detected.
+    #[structopt(long = "cosmac")]
+    cosmac: bool,
+    /// Enable SUPER-CHIP instructions and quirks.
+    #[structopt(long = "schip")]
+    schip: bool,
+    /// Enable XO-CHIP instructions and quirks.
+    #[structopt(long = "xochip")]
+    xochip: bool,
+    /// Halt when the program writes below 0x200, over the fonts.
+    #[structopt(long = "write-protect")]
+    write_protect: bool,
+    /// Set VF when Fx1E moves I past 0xFFF like the Amiga interpreter,
+    /// which e.g. Spacefight 2091! relies on.
+    #[structopt(long = "i-overflow-sets-vf")]
+    i_overflow_sets_vf: bool,
+    /// Reset VF to 0 after 8xy1/8xy2/8xy3 like the COSMAC VIP, which the
+    /// `--cosmac` quirks already do.
+    #[structopt(long = "reset-vf")]
+    reset_vf: bool,
+    /// Make Dxyn wait for the next 60Hz frame like the COSMAC VIP, which
+    /// stops flicker of games run at high clocks.
+    #[structopt(long = "display-wait")]
+    display_wait: bool,
+    /// Wrap sprites crossing the screen edge to the other side instead of
+    /// clipping them, like Octo.
+    #[structopt(long = "wrap-sprites")]
+    wrap_sprites: bool,
+    /// Address to load and start the ROM at, e.g. 0x600 for ETI 660 ROMs.
+    #[structopt(long = "start-addr", default_value = "0x200", parse(try_from_str = parse_hex))]
+    start_addr: u16,
+    /// File to keep SUPER-CHIP RPL flags (e.g. high scores) across runs.
+    #[structopt(long = "rpl-file")]
+    rpl_file: Option<PathBuf>,
+    /// Directory to save screenshots taken with F12 and recordings
+    /// toggled with F9 in.
+    #[structopt(long = "screenshot-dir", default_value = ".")]
+    screenshot_dir: PathBuf,
+    /// File to save the last screen to as a PBM image on exit.
+    #[structopt(long = "screenshot-on-exit")]
+    screenshot_on_exit: Option<PathBuf>,
+    /// File mapping keyboard characters to Chip8 keys, one `<char> <key>`
+    /// pair such as `q 4` per line. The QWERTY layout if not given.
+    /// Gamepad buttons are mapped the same way, e.g. `South 5`.
+    #[structopt(long = "keymap")]
+    keymap: Option<PathBuf>,
+    /// Color of lit pixels.
+    #[structopt(long = "fg", default_value = "white", parse(try_from_str = parse_color))]
+    fg: Color,
+    /// Color of unlit pixels.
+    #[structopt(long = "bg", default_value = "black", parse(try_from_str = parse_color))]
+    bg: Color,
+    /// Font of hex digits: standard, vip, dream6800 or eti660.
+    #[structopt(long = "font", default_value = "standard", parse(try_from_str = parse_font))]
+    font: Font,
+    /// Print a hexdump of RAM when the program halts.
+    #[structopt(long = "dump-ram")]
+    dump_ram: bool,
 }
 
-#[derive(Debug, Clone, Copy)]
-enum Filler {
-    Fill,
-    Unfill,
+fn parse_hex(s: &str) -> Result<u16, std::num::ParseIntError> {
+    u16::from_str_radix(s.trim_start_matches("0x"), 16)
 }
 
-impl std::convert::From<Filler> for Color {
-    fn from(f: Filler) -> Color {
-        match f {
-            Filler::Fill => White,
-            Filler::Unfill => Black,
-        }
+fn parse_fps(s: &str) -> Result<i32, String> {
+    match s.parse() {
+        Ok(fps) if fps > 0 => Ok(fps),
+        _ => Err(format!("fps must be a positive number, not `{}`", s)),
     }
 }
 
-impl std::convert::From<Filler> for u8 {
-    fn from(f: Filler) -> u8 {
-        match f {
-            Filler::Fill => 1,
-            Filler::Unfill => 0,
-        }
+fn parse_clock(s: &str) -> Result<u32, String> {
+    match s.parse() {
+        Ok(hz) if hz > 0 => Ok(hz),
+        _ => Err(format!("clock must be a positive number, not `{}`", s)),
+    }
+}
+
+fn parse_font(s: &str) -> Result<Font, String> {
+    match s {
+        "standard" => Ok(Font::Standard),
+        "vip" => Ok(Font::Vip),
+        "dream6800" => Ok(Font::Dream6800),
+        "eti660" => Ok(Font::Eti660),
+        _ => Err(format!("unknown font `{}`", s)),
+    }
+}
+
+/// Colors accepted by `--fg` and `--bg` with RGB values for screenshots.
+const COLORS: [(&str, Color, [u8; 3]); 8] = [
+    ("black", Black, [0, 0, 0]),
+    ("red", Red, [255, 0, 0]),
+    ("green", Green, [0, 255, 0]),
+    ("yellow", Yellow, [255, 255, 0]),
+    ("blue", Blue, [0, 0, 255]),
+    ("magenta", Magenta, [255, 0, 255]),
+    ("cyan", Cyan, [0, 255, 255]),
+    ("white", White, [255, 255, 255]),
+];
+
+/// Keyboard characters of the Chip8 keys by default.
+const DEFAULT_KEYS: &str = "1234qwerasdfzxcv";
+
+/// Chip8 keys of keyboard characters and gamepad buttons.
+struct Keymap {
+    keys: HashMap<char, core::Key>,
+    /// Buttons by gilrs name, e.g. `DPadUp`, remapped from their defaults.
+    #[cfg_attr(not(feature = "gilrs"), allow(dead_code))]
+    buttons: HashMap<String, core::Key>,
+}
+
+fn default_keys() -> HashMap<char, core::Key> {
+    DEFAULT_KEYS
+        .chars()
+        .filter_map(|c| core::Key::try_from_char(c).map(|k| (c, k)))
+        .collect()
+}
+
+fn default_keymap() -> Keymap {
+    Keymap {
+        keys: default_keys(),
+        buttons: HashMap::new(),
     }
 }
 
-impl std::convert::From<u8> for Filler {
-    fn from(f: u8) -> Filler {
-        match f {
-            1 => Filler::Fill,
-            _ => Filler::Unfill,
+#[cfg(feature = "gilrs")]
+fn is_button(name: &str) -> bool {
+    gamepad::button(name).is_some()
+}
+
+#[cfg(not(feature = "gilrs"))]
+fn is_button(_name: &str) -> bool {
+    false
+}
+
+/// Parse a keymap file of `<char> <key>` or `<button> <key>` lines, `#`
+/// starting a comment. Without any characters, the keyboard keeps the
+/// default layout.
+fn parse_keymap(text: &str) -> Result<Keymap, String> {
+    let mut keys = HashMap::new();
+    let mut buttons = HashMap::new();
+    for (n, line) in text.lines().enumerate() {
+        let n = n + 1;
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let (c, key) = match (words.next(), words.next(), words.next()) {
+            (Some(c), Some(key), None) if c.chars().count() == 1 => {
+                (c.chars().next().unwrap(), key)
+            }
+            (Some(b), Some(_), None) if !is_button(b) => {
+                return Err(format!("line {}: unknown gamepad button `{}`", n, b));
+            }
+            (Some(b), Some(key), None) => {
+                let key = parse_key(n, key)?;
+                if buttons.insert(b.to_string(), key).is_some() {
+                    return Err(format!("line {}: `{}` is mapped more than once", n, b));
+                }
+                continue;
+            }
+            _ => return Err(format!("line {}: expected `<char> <key>`", n)),
+        };
+        let key = parse_key(n, key)?;
+        if keys.insert(c, key).is_some() {
+            return Err(format!("line {}: `{}` is mapped more than once", n, c));
         }
     }
+    if keys.is_empty() {
+        keys = default_keys();
+    }
+    Ok(Keymap { keys, buttons })
+}
+
+/// Chip8 key `key` of a keymap file in hex, on line `n`.
+fn parse_key(n: usize, key: &str) -> Result<core::Key, String> {
+    u8::from_str_radix(key.trim_start_matches("0x"), 16)
+        .ok()
+        .and_then(core::Key::from_nibble)
+        .ok_or_else(|| format!("line {}: unknown Chip8 key `{}`", n, key))
+}
+
+fn parse_color(s: &str) -> Result<Color, String> {
+    COLORS
+        .iter()
+        .find(|(name, _, _)| name.eq_ignore_ascii_case(s))
+        .map(|(_, color, _)| *color)
+        .ok_or_else(|| {
+            let names: Vec<_> = COLORS.iter().map(|(name, _, _)| *name).collect();
+            format!(
+                "unknown color `{}`, expected one of {}",
+                s,
+                names.join(", ")
+            )
+        })
 }
 
+fn rgb(color: Color) -> [u8; 3] {
+    COLORS
+        .iter()
+        .find(|(_, c, _)| *c == color)
+        .map(|(_, _, rgb)| *rgb)
+        .unwrap_or([0, 0, 0])
+}
+
+/// Width of recordings in pixels, either resolution is scaled up to it.
+const RECORDING_WIDTH: usize = 512;
+
+/// Most frames kept in memory while recording, 30 seconds at 60 FPS.
+const MAX_RECORD_FRAMES: usize = 1800;
+
 struct DisplayAdaptor {
     console: Arc<Mutex<Console>>,
 }
@@ -70,64 +266,152 @@ impl DisplayAdaptor {
     fn new(console: Arc<Mutex<Console>>) -> DisplayAdaptor {
         DisplayAdaptor { console }
     }
-}
 
-impl Display for DisplayAdaptor {
-    fn draw(&self, x: u8, y: u8, data: Vec<u8>) -> Result<u8, ()> {
-        self.console.lock().unwrap().draw(x, y, data)
+    /// Lock the console, failing if a panic left it poisoned.
+    fn console(&self) -> Result<MutexGuard<'_, Console>, core::Error> {
+        self.console
+            .lock()
+            .map_err(|e| core::Error::Display(e.to_string()))
     }
+}
 
-    fn clear(&self) {
-        self.console.lock().unwrap().clear();
+impl Display for DisplayAdaptor {
+    fn present(&mut self, fb: &FrameBuffer) -> Result<(), core::Error> {
+        self.console()?.present(fb);
+        Ok(())
     }
-}
 
-fn bitarray(byte: u8) -> Vec<u8> {
-    let mut s = Vec::new();
-    for n in 0..8 {
-        s.push((byte >> (7 - n)) & 0x1);
+    fn clear(&mut self) -> Result<(), core::Error> {
+        self.console()?.rb.clear();
+        Ok(())
     }
-    s
 }
 
 struct Console {
     rb: RustBox,
     keyboard: mpsc::Sender<core::Key>,
-    /// Current screen buffer.
-    curr: [[u8; HEIGHT]; WIDTH],
+    /// Last screen presented by the emulator through `DisplayAdaptor`.
+    fb: FrameBuffer,
+    /// Terminal cells per pixel horizontally, and half cells vertically.
+    scale: usize,
+    /// Color of a pixel by the mask of XO-CHIP planes it's on in.
+    palette: [Color; 4],
+    /// Chip8 key of each keyboard character.
+    keymap: HashMap<char, core::Key>,
+    /// Commands entered since the emulator loop last took them.
+    commands: Vec<Command>,
+    /// File given by `--screenshot-on-exit`.
+    exit_screenshot: Option<PathBuf>,
+}
+
+/// What the emulator loop is asked to do by keys other than the keypad.
+#[derive(Debug, Clone, Copy)]
+enum Command {
+    /// Restart the ROM.
+    Reset,
+    /// Pause or resume the emulation.
+    TogglePause,
+    /// Save the screen to a file.
+    Screenshot,
+    /// Run faster by `--turbo-multiplier` or back to normal speed.
+    ToggleTurbo,
+    /// Start recording the screen, or stop and save the recording.
+    ToggleRecord,
+}
+
+/// Screens captured once a frame while recording.
+struct Recorder {
+    /// Frames of `HIRES_WIDTH` x `HIRES_HEIGHT` palette indices.
+    frames: Vec<Vec<u8>>,
+}
+
+impl Recorder {
+    fn new() -> Self {
+        Recorder { frames: Vec::new() }
+    }
+
+    /// Keep `frame` unless there are already `MAX_RECORD_FRAMES`.
+    fn capture(&mut self, frame: Vec<u8>) {
+        if self.frames.len() < MAX_RECORD_FRAMES {
+            self.frames.push(frame);
+            if self.frames.len() == MAX_RECORD_FRAMES {
+                warn!(
+                    "Recording reached {} frames, the rest isn't recorded",
+                    MAX_RECORD_FRAMES
+                );
+            }
+        }
+    }
+
+    /// Encode the frames as an animated GIF shown at `fps`.
+    fn save<W: Write>(&self, w: W, fps: i32, palette: &[[u8; 3]; 4]) -> std::io::Result<()> {
+        let scale = RECORDING_WIDTH / HIRES_WIDTH;
+        let (width, height) = (HIRES_WIDTH * scale, HIRES_HEIGHT * scale);
+        // GIF delays are in 1/100 seconds and viewers slow down ones below 2.
+        let delay = ((100 + fps / 2) / fps).max(2) as u16;
+        let mut enc = gif::Encoder::new(w, width as u16, height as u16, palette, delay)?;
+        let mut pixels = Vec::with_capacity(width * height);
+        let mut scaled = Vec::with_capacity(width);
+        for frame in &self.frames {
+            pixels.clear();
+            for row in frame.chunks(HIRES_WIDTH) {
+                scaled.clear();
+                for &p in row {
+                    scaled.resize(scaled.len() + scale, p);
+                }
+                for _ in 0..scale {
+                    pixels.extend_from_slice(&scaled);
+                }
+            }
+            enc.frame(&pixels)?;
+        }
+        enc.finish()
+    }
 }
 
 impl Console {
-    fn new(rb: RustBox, keyboard: mpsc::Sender<core::Key>) -> Self {
-        let console = Console {
+    fn new(
+        rb: RustBox,
+        keyboard: mpsc::Sender<core::Key>,
+        keymap: HashMap<char, core::Key>,
+        scale: usize,
+        fg: Color,
+        bg: Color,
+    ) -> Self {
+        Console {
             rb,
             keyboard,
-            curr: [[0; HEIGHT]; WIDTH],
-        };
-        for x in 0..WIDTH {
-            for y in 0..HEIGHT {
-                console.draw_pixel(x, y, Filler::Unfill);
-            }
+            keymap,
+            fb: FrameBuffer::new(),
+            scale: scale.max(1),
+            palette: [bg, fg, Cyan, Magenta],
+            commands: Vec::new(),
+            exit_screenshot: None,
         }
-        console
     }
 
-    fn peek_keyevent(&self) -> Option<()> {
+    fn peek_keyevent(&mut self) -> Option<()> {
         match self.rb.peek_event(Duration::from_millis(0), false) {
             Ok(rustbox::Event::KeyEvent(key)) => match key {
                 Key::Esc => {
+                    self.save_exit_screenshot();
                     std::process::exit(0);
                 }
-                Key::Char(c) => {
-                    let k = core::Key::from(c);
-                    if k.0 != 0x99 {
+                Key::F(5) => self.commands.push(Command::Reset),
+                Key::Tab => self.commands.push(Command::ToggleTurbo),
+                Key::F(9) => self.commands.push(Command::ToggleRecord),
+                Key::F(12) => self.commands.push(Command::Screenshot),
+                Key::Char(c) => match self.keymap.get(&c) {
+                    Some(&k) => {
                         debug!("sending key {:?}", c);
                         self.keyboard
                             .send(k)
                             .map_err(|e| error!("Keyboard error: {}", e))
                             .unwrap();
                     }
-                }
+                    None if c == 'p' => self.commands.push(Command::TogglePause),
+                    None => {}
+                },
                 _ => {}
             },
             Ok(rustbox::Event::NoEvent) => {
@@ -142,101 +426,375 @@ impl Console {
         Some(())
     }
 
-    fn draw(&mut self, x: u8, y: u8, data: Vec<u8>) -> Result<u8, ()> {
-        let x = x as usize;
-        let y = y as usize;
-        let mut vf = 0;
-        for (iy, b) in data.iter().enumerate() {
-            let next = bitarray(*b);
-            for (ix, nb) in next.iter().enumerate() {
-                if x + ix >= WIDTH || y + iy >= HEIGHT {
-                    continue;
-                }
+    /// Redraw the cells showing pixel at `x`, `y` from `fb`.
+    fn draw_pixel(&self, x: usize, y: usize) {
+        let first = y * self.scale / 2;
+        let last = ((y + 1) * self.scale - 1) / 2;
+        for cx in x * self.scale..(x + 1) * self.scale {
+            for cy in first..=last {
+                self.draw_cell(cx, cy);
+            }
+        }
+    }
 
-                let cb = self.curr[x + ix][y + iy];
-                match (cb, nb) {
-                    (0, 0) => {}
-                    (0, 1) |(1, 0) => {
-                        self.draw_pixel(x + ix, y + iy, Filler::Fill);
-                    }
-                    (1, 1) => {
-                        vf = 1;
-                        self.draw_pixel(x + ix, y + iy, Filler::Unfill);
-                    }
-                    _ => {
-                        panic!("Illegal bit value: cb={}, nb={}", cb, nb);
-                    }
-                }
-                self.curr[x + ix][y + iy] ^= nb;
+    /// Draw the two half cell pixels of a cell, the bottom one being black
+    /// below the screen.
+    fn draw_cell(&self, cx: usize, cy: usize) {
+        let x = cx / self.scale;
+        let pixel = |half: usize| {
+            let y = half / self.scale;
+            if y < self.fb.resolution().height() {
+                self.palette[(self.fb.pixels()[x][y] & 0x3) as usize]
+            } else {
+                Black
             }
+        };
+        let (top, bottom) = (pixel(cy * 2), pixel(cy * 2 + 1));
+        if top == bottom {
+            self.rb.print_char(cx, cy, RB_BOLD, White, bottom, PIXEL);
+        } else {
+            self.rb.print_char(cx, cy, RB_BOLD, top, bottom, UPPER_HALF);
         }
+    }
 
-        Ok(vf)
+    /// Terminal rows the screen takes.
+    fn rows(&self) -> usize {
+        (self.fb.resolution().height() * self.scale + 1) / 2
     }
 
-    fn draw_pixel(&self, x: usize, y: usize, fill: Filler) {
-        // debug!("Draw pixel {} {} {:?}", x, y, fill);
-        self.rb.print_char(x, y, RB_BOLD, White, fill.into(), PIXEL);
+    /// Take `fb` as the screen and draw its changed pixels, which are
+    /// shown by the next `flush`.
+    fn present(&mut self, fb: &FrameBuffer) {
+        let resized = fb.resolution() != self.fb.resolution();
+        self.fb.clone_from(fb);
+        if resized {
+            self.check_size();
+        }
+        for (x, y, _) in fb.dirty() {
+            self.draw_pixel(x, y);
+        }
     }
 
+    /// Show what was presented since the last call.
     fn flush(&mut self) {
         self.rb.present();
     }
 
-    fn clear(&mut self) {
-        self.rb.clear();
-        for x in 0..WIDTH {
-            for y in 0..HEIGHT {
-                self.curr[x][y] = 0;
-                self.draw_pixel(x, y, Filler::Unfill);
+    /// Warn if the terminal can't show the whole screen and the status line
+    /// below it, e.g. SUPER-CHIP's 128x64 in an 80 columns wide terminal.
+    fn check_size(&self) {
+        let res = self.fb.resolution();
+        let (cols, rows) = (res.width() * self.scale, self.rows() + 1);
+        if self.rb.width() < cols || self.rb.height() < rows {
+            warn!(
+                "Terminal of {}x{} cells is too small for {}x{} pixels, which need {}x{}",
+                self.rb.width(),
+                self.rb.height(),
+                res.width(),
+                res.height(),
+                cols,
+                rows
+            );
+        }
+    }
+
+    /// Show `msg` on the line below the screen.
+    fn show_status(&self, msg: &str) {
+        self.rb.print(0, self.rows(), RB_BOLD, White, Black, msg);
+    }
+
+    /// Show or hide the pause indicator at the right end of the status line.
+    fn show_paused(&self, paused: bool) {
+        let msg = if paused { "PAUSED" } else { "      " };
+        self.rb.print(
+            self.fb.resolution().width() * self.scale - msg.len(),
+            self.rows(),
+            RB_BOLD,
+            White,
+            Black,
+            msg,
+        );
+    }
+
+    /// RGB values of `palette`.
+    fn palette_rgb(&self) -> [[u8; 3]; 4] {
+        let mut rgbs = [[0; 3]; 4];
+        for (to, color) in rgbs.iter_mut().zip(self.palette.iter()) {
+            *to = rgb(*color);
+        }
+        rgbs
+    }
+
+    /// Save the screen as a PBM image if asked for by `--screenshot-on-exit`.
+    fn save_exit_screenshot(&self) {
+        if let Some(path) = &self.exit_screenshot {
+            match std::fs::write(path, self.fb.to_pbm()) {
+                Ok(()) => info!("Saved screenshot to {}", path.display()),
+                Err(e) => error!("Unable to save screenshot: {}", e),
+            }
+        }
+    }
+
+    /// Palette indices of the screen row by row, scaled up to the high
+    /// resolution.
+    fn frame(&self) -> Vec<u8> {
+        let width = self.fb.resolution().width();
+        let scale = HIRES_WIDTH / width;
+        let pixels = self.fb.snapshot();
+        let mut frame = Vec::with_capacity(HIRES_WIDTH * HIRES_HEIGHT);
+        for y in 0..HIRES_HEIGHT {
+            for x in 0..HIRES_WIDTH {
+                frame.push(pixels[y / scale * width + x / scale]);
             }
         }
+        frame
     }
 }
 
-fn emuloop(mut chip8: Chip8, console: Arc<Mutex<Console>>, opts: Args) -> Result<(), ()> {
-    let frame = Duration::from_millis((1000 / opts.fps) as u64);
+/// Why the emulator stopped, to report once the terminal is restored.
+struct Stopped {
+    halt: Halt,
+    /// Hexdump of RAM if asked for by `--dump-ram`.
+    ram: Option<String>,
+}
+
+/// Run until the program halts, returning why.
+fn emuloop(mut chip8: Chip8, console: Arc<Mutex<Console>>, opts: Args) -> Stopped {
+    let mut rpl = chip8.cpu.rpl();
+    let mut recorder: Option<Recorder> = None;
+    let mut turbo = false;
     loop {
-        let now = Instant::now();
+        let speed = if turbo { opts.turbo_multiplier } else { 1 };
+        let clock_hz = chip8.cpu.clock_hz.saturating_mul(speed);
+        let res = chip8.run_at(clock_hz, opts.fps as u32, |chip8| {
+            frame(chip8, &console, &opts, &mut rpl, &mut recorder, &mut turbo)
+        });
+        match res {
+            // Keep showing the last screen until Esc is pressed.
+            Err(Halt::SpinLoop(_)) => console.lock().unwrap().show_status("program finished"),
+            Err(halt) => {
+                console.lock().unwrap().save_exit_screenshot();
+                let ram = if opts.dump_ram {
+                    Some(chip8.ram.hexdump(0, chip8.ram.size()))
+                } else {
+                    None
+                };
+                return Stopped { halt, ram };
+            }
+            Ok(()) => {}
+        }
+    }
+}
 
-        // Run Chip8 Instructions.
-        chip8.tick();
+/// Show what was drawn in a frame and handle the keys pressed meanwhile.
+/// Breaks when the speed has to change.
+fn frame(
+    chip8: &mut Chip8,
+    console: &Mutex<Console>,
+    opts: &Args,
+    rpl: &mut [u8; 8],
+    recorder: &mut Option<Recorder>,
+    turbo: &mut bool,
+) -> ControlFlow<()> {
+    if let Some(path) = &opts.rpl_file {
+        if chip8.cpu.rpl() != *rpl {
+            *rpl = chip8.cpu.rpl();
+            if let Err(e) = save_rpl(chip8, path) {
+                error!("Unable to save RPL flags: {:?}", e);
+            }
+        }
+    }
 
-        match console.lock() {
-            Ok(mut c) => {
-                loop {
-                    if c.peek_keyevent().is_none() {
-                        break;
-                    }
+    let mut commands = Vec::new();
+    match console.lock() {
+        Ok(mut c) => {
+            loop {
+                if c.peek_keyevent().is_none() {
+                    break;
                 }
-                c.flush();
             }
-            Err(e) => {
-                error!("Unable to unlock Console: {}", e);
+            c.flush();
+            commands = std::mem::take(&mut c.commands);
+            if let Some(rec) = recorder.as_mut() {
+                rec.capture(c.frame());
             }
         }
+        Err(e) => {
+            error!("Unable to unlock Console: {}", e);
+        }
+    }
 
-        if let Some(remaining) = frame.checked_sub(now.elapsed()) {
-            sleep(remaining);
+    // Console is unlocked by now since the emulator draws through it.
+    let mut flow = ControlFlow::Continue(());
+    for cmd in commands {
+        match cmd {
+            Command::Reset => {
+                if let Err(e) = chip8.reset() {
+                    error!("Unable to reset: {}", e);
+                }
+            }
+            Command::Screenshot => {
+                if let Err(e) = save_screenshot(console, &opts.screenshot_dir) {
+                    error!("Unable to save screenshot: {}", e);
+                }
+            }
+            Command::ToggleRecord => match recorder.take() {
+                Some(rec) => {
+                    let palette = console.lock().unwrap().palette_rgb();
+                    let res = save_recording(&rec, &opts.screenshot_dir, opts.fps, &palette);
+                    if let Err(e) = res {
+                        error!("Unable to save recording: {}", e);
+                    }
+                }
+                None => {
+                    info!("Started recording");
+                    *recorder = Some(Recorder::new());
+                }
+            },
+            Command::TogglePause => {
+                if chip8.is_paused() {
+                    chip8.resume();
+                } else {
+                    chip8.pause();
+                }
+                let mut c = console.lock().unwrap();
+                c.show_paused(chip8.is_paused());
+                c.flush();
+            }
+            Command::ToggleTurbo => {
+                *turbo = !*turbo;
+                info!("Turbo {}", if *turbo { "on" } else { "off" });
+                flow = ControlFlow::Break(());
+            }
         }
     }
+    flow
+}
+
+fn save_screenshot(console: &Mutex<Console>, dir: &Path) -> std::io::Result<()> {
+    let path = timestamped(dir, "pbm");
+    std::fs::write(&path, console.lock().unwrap().fb.to_pbm())?;
+    info!("Saved screenshot to {}", path.display());
+    Ok(())
 }
 
-fn run(opts: Args) -> Result<(), ()> {
+fn save_recording(
+    rec: &Recorder,
+    dir: &Path,
+    fps: i32,
+    palette: &[[u8; 3]; 4],
+) -> std::io::Result<()> {
+    let path = timestamped(dir, "gif");
+    let file = std::io::BufWriter::new(std::fs::File::create(&path)?);
+    rec.save(file, fps, palette)?;
+    info!(
+        "Saved recording of {} frames to {}",
+        rec.frames.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Path in `dir` named after the current time, so that files don't collide.
+fn timestamped(dir: &Path, ext: &str) -> PathBuf {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    dir.join(format!("chip8-{}.{}", now.as_millis(), ext))
+}
+
+fn save_rpl(chip8: &Chip8, path: &Path) -> Result<(), core::Error> {
+    let file = std::fs::File::create(path)?;
+    chip8.cpu.save_rpl(file)
+}
+
+fn run(opts: Args) -> Result<Stopped, core::Error> {
+    // Report a broken keymap before the terminal is taken over.
+    let keymap = match &opts.keymap {
+        Some(path) => match std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| parse_keymap(&text))
+        {
+            Ok(keymap) => keymap,
+            Err(e) => {
+                eprintln!("Invalid keymap {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        },
+        None => default_keymap(),
+    };
     let (itx, irx) = mpsc::channel();
+    #[cfg(feature = "gilrs")]
+    gamepad::spawn(itx.clone(), &keymap.buttons);
     let rb = RustBox::init(Default::default()).unwrap();
-    let console = Arc::new(Mutex::new(Console::new(rb, itx)));
+    let mut console = Console::new(rb, itx, keymap.keys, opts.scale, opts.fg, opts.bg);
+    console.exit_screenshot = opts.screenshot_on_exit.clone();
+    let console = Arc::new(Mutex::new(console));
     let adaptor = DisplayAdaptor::new(console.clone());
 
-    let mut chip8 = Chip8::new(Box::new(adaptor), irx);
-    let rom = &opts.rom.canonicalize().unwrap();
-    let file = std::fs::File::open(&rom.to_str().unwrap()).unwrap();
-    chip8.ram.load(file).unwrap();
-    emuloop(chip8, console, opts)
+    let rom = std::fs::read(opts.rom.canonicalize().unwrap()).unwrap();
+    let mut quirks = if opts.xochip {
+        Quirks::xochip()
+    } else if opts.schip {
+        Quirks::schip()
+    } else if opts.cosmac {
+        Quirks::chip8()
+    } else {
+        Chip8::detect_quirks(&rom).unwrap_or_else(|| {
+            info!("ROM isn't in the quirks database, using default quirks");
+            Quirks::default()
+        })
+    };
+    quirks.write_protect = opts.write_protect;
+    quirks.i_overflow_sets_vf |= opts.i_overflow_sets_vf;
+    quirks.reset_vf |= opts.reset_vf;
+    quirks.display_wait |= opts.display_wait;
+    quirks.wrap_sprites |= opts.wrap_sprites;
+    let mut chip8 = Chip8::builder()
+        .display(Box::new(adaptor))
+        .input(Box::new(irx))
+        .quirks(quirks)
+        .clock_hz(opts.clock)
+        .start_addr(opts.start_addr)
+        .font(opts.font)
+        .trace(DEFAULT_TRACE_DEPTH)
+        .build();
+    if let Some(path) = &opts.rpl_file {
+        // Missing file just means nothing has been saved yet.
+        if let Ok(file) = std::fs::File::open(path) {
+            if let Err(e) = chip8.cpu.load_rpl(file) {
+                error!("Unable to load RPL flags: {:?}", e);
+            }
+        }
+    }
+    chip8.load_rom_bytes(&rom)?;
+    if let Some(rom_info) = chip8.rom_info() {
+        info!("Loaded {} from {}", rom_info, opts.rom.display());
+    }
+    Ok(emuloop(chip8, console, opts))
 }
 
-fn main() -> Result<(), ()> {
+fn main() {
     log4rs::init_file("logger.yml", Default::default()).unwrap();
     let opts = Args::from_args();
-    run(opts)
+    // Terminal is restored once `run` returns and drops the console.
+    let stopped = match run(opts) {
+        Ok(res) => res,
+        Err(e) => {
+            error!("{}", e);
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(ram) = &stopped.ram {
+        print!("{}", ram);
+    }
+    let halt = stopped.halt;
+    if halt == Halt::Exit {
+        info!("{}", halt);
+        return;
+    }
+    error!("{}", halt);
+    eprintln!("{}", halt);
+    std::process::exit(1);
 }